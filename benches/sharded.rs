@@ -0,0 +1,56 @@
+//! Times processing of `tests/data/03-10k_records-input.csv`, repeated `CRAB_BENCH_SCALE` times
+//! (10 by default) with shifted transaction ids, for an increasing number of ledger threads.
+//!
+//! Run with `cargo bench --bench sharded`.
+
+use crab::app::{process_reader, Config};
+use std::time::Instant;
+
+const INPUT: &str = "tests/data/03-10k_records-input.csv";
+
+fn scaled_input(scale: u32) -> String {
+    let original = std::fs::read_to_string(INPUT).unwrap();
+    let mut lines = original.lines();
+    let header = lines.next().unwrap();
+    let records: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    let stride = records
+        .iter()
+        .map(|fields| fields[2].parse::<u32>().unwrap())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut input = format!("{header}\n");
+    for copy in 0..scale {
+        for fields in &records {
+            let tx = fields[2].parse::<u32>().unwrap() + copy * stride;
+            input.push_str(&format!(
+                "{},{},{},{}\n",
+                fields[0], fields[1], tx, fields[3]
+            ));
+        }
+    }
+    input
+}
+
+fn main() {
+    let scale = std::env::var("CRAB_BENCH_SCALE")
+        .ok()
+        .and_then(|scale| scale.parse().ok())
+        .unwrap_or(10);
+    let input = scaled_input(scale);
+    println!("{} records", input.lines().count() - 1);
+    for threads in [1, 2, 4, 8] {
+        let config = Config {
+            threads,
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let ledger = process_reader(input.as_bytes(), &config).unwrap();
+        let elapsed = start.elapsed();
+        println!(
+            "{threads} thread(s): {:>8.2?} ({} accounts)",
+            elapsed,
+            ledger.accounts().count()
+        );
+    }
+}
@@ -1,10 +1,32 @@
+use std::{collections::BTreeMap, error, fmt, str};
+
 pub type Number = rust_decimal::Decimal;
+/// Time of a transaction, in the unit used by the input, such as seconds since the Unix epoch.
+pub type Timestamp = u64;
 pub use rust_decimal_macros::dec as num;
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone, Default)]
+#[derive(
+    Debug,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Copy,
+    Clone,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 pub struct ClientId(pub u16);
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum AccountError {
     Overflow {
         available: Number,
@@ -21,14 +43,111 @@ pub enum AccountError {
 
 pub type AccountResult = Result<(), AccountError>;
 
-#[derive(Copy, Clone, Default, Debug, PartialEq)]
-pub struct Account {
+impl AccountError {
+    /// Stable identifier of the error kind, suitable for logs and reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::Overflow { .. } => "account_overflow",
+            AccountError::Underflow { .. } => "account_underflow",
+            AccountError::FrozenAccount(_) => "frozen_account",
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Overflow {
+                available,
+                held,
+                transaction_amount,
+            } => write!(
+                f,
+                "amount {transaction_amount} overflows balance (available {available}, held {held})"
+            ),
+            AccountError::Underflow {
+                available,
+                held,
+                transaction_amount,
+            } => write!(
+                f,
+                "insufficient funds for amount {transaction_amount} (available {available}, held {held})"
+            ),
+            AccountError::FrozenAccount(_) => write!(f, "account is frozen"),
+        }
+    }
+}
+
+impl error::Error for AccountError {}
+
+/// ISO 4217 currency code.
+///
+/// Transactions that don't specify a currency use `XXX`, the code ISO 4217 reserves for
+/// transactions where no currency is involved.
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const NONE: Currency = Currency(*b"XXX");
+
+    pub fn as_str(&self) -> &str {
+        // Only built from ASCII letters.
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::NONE
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl str::FromStr for Currency {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code.as_bytes() {
+            [a, b, c] if code.bytes().all(|byte| byte.is_ascii_alphabetic()) => Ok(Currency([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+                c.to_ascii_uppercase(),
+            ])),
+            _ => Err(format!("invalid currency code `{code}`")),
+        }
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Currency> for String {
+    fn from(value: Currency) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+/// Funds held in a single currency.
+#[derive(Copy, Clone, Default, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Balance {
     available: Number,
     held: Number,
-    locked: bool,
 }
 
-impl Account {
+impl Balance {
     pub fn total(&self) -> Number {
         self.available + self.held
     }
@@ -38,17 +157,7 @@ impl Account {
     pub fn held(&self) -> Number {
         self.held
     }
-    pub fn locked(&self) -> bool {
-        self.locked
-    }
-    pub fn check_locked(&mut self) -> AccountResult {
-        if self.locked {
-            Err(AccountError::FrozenAccount(*self))
-        } else {
-            Ok(())
-        }
-    }
-    pub fn deposit(&mut self, amount: Number) -> AccountResult {
+    fn deposit(&mut self, amount: Number) -> AccountResult {
         self.available = self
             .available
             .checked_add(amount)
@@ -59,8 +168,7 @@ impl Account {
             })?;
         Ok(())
     }
-    pub fn withdraw(&mut self, amount: Number) -> AccountResult {
-        self.check_locked()?;
+    fn withdraw(&mut self, amount: Number) -> AccountResult {
         if self.available < amount {
             return Err(AccountError::Underflow {
                 available: self.available,
@@ -71,7 +179,7 @@ impl Account {
         self.available -= amount;
         Ok(())
     }
-    pub fn dispute(&mut self, amount: Number) -> AccountResult {
+    fn dispute(&mut self, amount: Number) -> AccountResult {
         let available = self
             .available
             .checked_sub(amount)
@@ -92,7 +200,7 @@ impl Account {
         self.held = held;
         Ok(())
     }
-    pub fn resolve(&mut self, amount: Number) -> AccountResult {
+    fn resolve(&mut self, amount: Number) -> AccountResult {
         let available = self
             .available
             .checked_add(amount)
@@ -114,17 +222,195 @@ impl Account {
         self.held = held;
         Ok(())
     }
-    pub fn chargeback(&mut self, amount: Number) {
+    fn chargeback(&mut self, amount: Number) {
         self.held -= amount;
+    }
+    fn dispute_withdrawal(&mut self, amount: Number) -> AccountResult {
+        self.held = self
+            .held
+            .checked_add(amount)
+            .ok_or(AccountError::Overflow {
+                available: self.available,
+                held: self.held,
+                transaction_amount: amount,
+            })?;
+        Ok(())
+    }
+    fn resolve_withdrawal(&mut self, amount: Number) -> AccountResult {
+        self.held = self
+            .held
+            .checked_sub(amount)
+            .ok_or(AccountError::Underflow {
+                available: self.available,
+                held: self.held,
+                transaction_amount: amount,
+            })?;
+        Ok(())
+    }
+    fn chargeback_withdrawal(&mut self, amount: Number) -> AccountResult {
+        let available = self
+            .available
+            .checked_add(amount)
+            .ok_or(AccountError::Overflow {
+                available: self.available,
+                held: self.held,
+                transaction_amount: amount,
+            })?;
+        self.available = available;
+        self.held -= amount;
+        Ok(())
+    }
+}
+
+/// A client account, holding a balance per currency.
+#[derive(Clone, Default, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Account {
+    balances: BTreeMap<Currency, Balance>,
+    locked: bool,
+    /// Chargebacks that locked the account and weren't reversed since it was last unlocked.
+    #[serde(default)]
+    chargebacks: u32,
+    /// Latest timestamp of the transactions of the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+}
+
+impl Account {
+    /// Total funds in [`Currency::NONE`]. See [`Account::balance`] for other currencies.
+    pub fn total(&self) -> Number {
+        self.balance(Currency::NONE).total()
+    }
+    /// Available funds in [`Currency::NONE`]. See [`Account::balance`] for other currencies.
+    pub fn available(&self) -> Number {
+        self.balance(Currency::NONE).available()
+    }
+    /// Held funds in [`Currency::NONE`]. See [`Account::balance`] for other currencies.
+    pub fn held(&self) -> Number {
+        self.balance(Currency::NONE).held()
+    }
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+    /// Iterates over the balances of the currencies this account has operated on, in ascending
+    /// currency code order.
+    pub fn balances(&self) -> impl Iterator<Item = (Currency, Balance)> + '_ {
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (*currency, *balance))
+    }
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+    pub fn check_locked(&mut self) -> AccountResult {
+        if self.locked {
+            Err(AccountError::FrozenAccount(self.clone()))
+        } else {
+            Ok(())
+        }
+    }
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.chargebacks = 0;
+    }
+    /// Locks the account for a chargeback.
+    fn lock_for_chargeback(&mut self) {
         self.locked = true;
+        self.chargebacks += 1;
+    }
+    /// Lifts the lock of a chargeback that was reversed, unless others still stand.
+    fn lift_chargeback(&mut self) {
+        self.chargebacks = self.chargebacks.saturating_sub(1);
+        if self.chargebacks == 0 {
+            self.locked = false;
+        }
+    }
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+    /// Records a transaction of the client at `timestamp`, which is kept if it is the latest.
+    pub(crate) fn record_timestamp(&mut self, timestamp: Timestamp) {
+        self.timestamp = self.timestamp.max(Some(timestamp));
+    }
+    /// Applies `operation` to a copy of the balance in `currency`, and only stores it back if
+    /// the operation succeeds.
+    fn update<F>(&mut self, currency: Currency, operation: F) -> AccountResult
+    where
+        F: FnOnce(&mut Balance) -> AccountResult,
+    {
+        let mut balance = self.balance(currency);
+        operation(&mut balance)?;
+        self.balances.insert(currency, balance);
+        Ok(())
+    }
+    pub fn deposit(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.deposit(amount))
+    }
+    pub fn withdraw(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.check_locked()?;
+        self.update(currency, |balance| balance.withdraw(amount))
+    }
+    pub fn dispute(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.dispute(amount))
+    }
+    pub fn resolve(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.resolve(amount))
+    }
+    pub fn chargeback(&mut self, currency: Currency, amount: Number) {
+        let mut balance = self.balance(currency);
+        balance.chargeback(amount);
+        self.balances.insert(currency, balance);
+        self.lock_for_chargeback();
+    }
+    /// Takes back `amount` credited by a chargeback that is being reversed, which a lock
+    /// doesn't prevent.
+    pub fn reclaim(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.withdraw(amount))
+    }
+    /// Restores `amount` of a charged back deposit, lifting the lock of the chargeback.
+    pub fn represent(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.deposit(currency, amount)?;
+        self.lift_chargeback();
+        Ok(())
+    }
+    /// Takes back the amount a chargeback of a disputed withdrawal returned, lifting the lock of
+    /// the chargeback.
+    pub fn represent_withdrawal(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.reclaim(currency, amount)?;
+        self.lift_chargeback();
+        Ok(())
+    }
+    /// Holds the amount of a disputed withdrawal as a provisional credit.
+    pub fn dispute_withdrawal(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.dispute_withdrawal(amount))
+    }
+    /// Drops the provisional credit of a disputed withdrawal, leaving the withdrawal in place.
+    pub fn resolve_withdrawal(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.resolve_withdrawal(amount))
+    }
+    /// Turns the provisional credit of a disputed withdrawal into available funds, reversing the
+    /// withdrawal.
+    pub fn chargeback_withdrawal(&mut self, currency: Currency, amount: Number) -> AccountResult {
+        self.update(currency, |balance| balance.chargeback_withdrawal(amount))?;
+        self.lock_for_chargeback();
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod account_tests {
     use super::num;
+    use super::Currency;
     use super::Number;
 
+    #[test]
+    fn parse_currency() {
+        assert_eq!("eur".parse::<Currency>().unwrap().as_str(), "EUR");
+        assert_eq!(Currency::default().as_str(), "XXX");
+        assert!("EURO".parse::<Currency>().is_err());
+        assert!("E1R".parse::<Currency>().is_err());
+        assert!("".parse::<Currency>().is_err());
+    }
+
     #[test]
     fn verify_precision() {
         let mut a = Number::ZERO;
@@ -1,16 +1,115 @@
-use std::{fs, io, sync::mpsc, thread};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::{cmp::Reverse, fmt, fs, io, io::Seek, path::Path, path::PathBuf, thread};
 
-use super::account::{ClientId, Number};
+use super::account::{Balance, ClientId, Currency, Number, Timestamp};
+use super::journal::{self, Journal, JournalError, Recovery};
 use super::ledger::Ledger;
-use super::transactions::{Operation, Transaction, TransactionId};
+use super::rejects::{error_chain, RejectWriter};
+use super::transactions::{
+    DisputePolicy, DisputeWindow, LockPolicy, Operation, Precision, Transaction, TransactionError,
+    TransactionId, TransactionResult,
+};
 
-fn create_reader(path: &String) -> csv::Reader<io::BufReader<fs::File>> {
-    let file = fs::File::open(path).unwrap();
-    let reader = io::BufReader::new(file);
-    csv::Reader::from_reader(reader)
+/// Records buffered between the CSV reader and each ledger thread when
+/// [`Config::channel_capacity`] isn't set.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Columns every input file must have, in any order.
+const REQUIRED_HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+fn create_reader<R: io::Read>(input: R) -> csv::Reader<io::BufReader<R>> {
+    let reader = io::BufReader::new(input);
+    // Records are read flexibly so that rows with a wrong number of fields can still be
+    // reported with their content, instead of being dropped by the CSV reader.
+    csv::ReaderBuilder::new().flexible(true).from_reader(reader)
 }
 
-#[derive(serde::Deserialize)]
+fn check_headers(headers: &csv::StringRecord) -> Result<(), AppError> {
+    let valid = REQUIRED_HEADERS
+        .iter()
+        .all(|required| headers.iter().any(|header| header == *required));
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::BadHeader(headers.clone()))
+    }
+}
+
+/// Errors that abort processing of an input.
+#[derive(Debug)]
+pub enum AppError {
+    Io(io::Error),
+    BadHeader(csv::StringRecord),
+    MalformedRecord {
+        line: u64,
+        byte: u64,
+        message: String,
+    },
+    State(serde_json::Error),
+    Journal(JournalError),
+    /// Options of the [`Config`] that can't be combined.
+    Config(&'static str),
+}
+
+impl AppError {
+    /// Process exit code reported for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Io(_) => 1,
+            AppError::BadHeader(_) => 2,
+            AppError::MalformedRecord { .. } => 3,
+            AppError::State(_) => 4,
+            AppError::Journal(_) => 5,
+            AppError::Config(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Io(err) => write!(f, "{}", err),
+            AppError::BadHeader(headers) => write!(
+                f,
+                "bad header {:?}, expected columns {}",
+                headers.iter().collect::<Vec<&str>>(),
+                REQUIRED_HEADERS.join(",")
+            ),
+            AppError::MalformedRecord {
+                line,
+                byte,
+                message,
+            } => write!(
+                f,
+                "malformed record at line {line} (byte {byte}): {message}"
+            ),
+            AppError::State(err) => write!(f, "invalid ledger state: {}", err),
+            AppError::Journal(err) => write!(f, "journal recovery failed: {}", err),
+            AppError::Config(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Io(err) => Some(err),
+            AppError::State(err) => Some(err),
+            AppError::Journal(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(value: io::Error) -> Self {
+        AppError::Io(value)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 enum TransactionType {
     Deposit,
@@ -18,6 +117,10 @@ enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    Transfer,
+    Representment,
+    PreArbitration,
 }
 
 impl From<TransactionType> for Operation {
@@ -28,6 +131,10 @@ impl From<TransactionType> for Operation {
             TransactionType::Dispute => Operation::Dispute,
             TransactionType::Resolve => Operation::Resolve,
             TransactionType::Chargeback => Operation::Chargeback,
+            TransactionType::Unlock => Operation::Unlock,
+            TransactionType::Transfer => Operation::Transfer,
+            TransactionType::Representment => Operation::Representment,
+            TransactionType::PreArbitration => Operation::PreArbitration,
         }
     }
 }
@@ -39,78 +146,816 @@ struct CsvTransactionRecord {
     client: u16,
     tx: u32,
     amount: Option<Number>,
+    #[serde(default)]
+    currency: Option<Currency>,
+    /// Client receiving a transfer, whose sender is `client`.
+    #[serde(default)]
+    to: Option<u16>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+}
+
+impl TryFrom<CsvTransactionRecord> for Transaction {
+    type Error = TransactionError;
+
+    /// Builds the transaction described by a record, checking that it has an amount if its
+    /// operation needs one, and none if it can't have one. Disputes may have an amount, to
+    /// dispute only part of a transaction.
+    fn try_from(record: CsvTransactionRecord) -> Result<Self, Self::Error> {
+        let transaction_id = TransactionId(record.tx);
+        let operation = Operation::from(record.tx_type);
+        let amount = match (operation.has_amount(), record.amount) {
+            (true, Some(amount)) => amount,
+            (true, None) => return Err(TransactionError::MissingAmount(transaction_id)),
+            (false, Some(amount)) if operation == Operation::Dispute => {
+                if amount.is_zero() {
+                    return Err(TransactionError::InvalidAmount(transaction_id, amount));
+                }
+                amount
+            }
+            (false, None) => Number::ZERO,
+            (false, Some(_)) => return Err(TransactionError::UnexpectedAmount(transaction_id)),
+        };
+        let mut transaction = Transaction::new(ClientId(record.client), amount, operation)
+            .with_currency(record.currency.unwrap_or_default());
+        if let Some(to) = record.to {
+            transaction = transaction.with_counterparty(ClientId(to));
+        }
+        if let Some(timestamp) = record.timestamp {
+            transaction = transaction.with_timestamp(timestamp);
+        }
+        Ok(transaction)
+    }
 }
 
 #[derive(serde::Serialize)]
 struct CsvAccountRecord {
     client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
     available: String,
     held: String,
     total: String,
     locked: bool,
 }
 
-fn process(
-    ledger: &mut Ledger,
-    transaction_id: TransactionId,
-    transaction: &Transaction,
-    print_error: bool,
-) {
-    match ledger.apply_transaction(transaction_id, transaction) {
-        Ok(()) => {}
-        Err(err) => {
-            if print_error {
-                eprintln!("error: {:?}", err);
-            }
+/// Options controlling how transactions are processed.
+#[derive(Default)]
+pub struct Config {
+    /// Print rejected transactions to stderr.
+    pub debug: bool,
+    /// Write a CSV report of every rejected transaction to this path.
+    pub rejects: Option<PathBuf>,
+    /// Abort on the first record that can't be parsed instead of skipping it.
+    pub strict: bool,
+    /// Order of the accounts in the output.
+    pub sort: SortOrder,
+    /// Which transactions can be disputed.
+    pub dispute_policy: DisputePolicy,
+    /// How long transactions can be disputed before they are evicted from the ledger. A bounded
+    /// window spans the transactions of all clients, so it can't be combined with several
+    /// threads.
+    pub dispute_window: DisputeWindow,
+    /// Which operations are allowed on locked accounts.
+    pub lock_policy: LockPolicy,
+    /// Decimal places accepted in amounts, and how to round amounts with more.
+    pub precision: Precision,
+    /// How much older than the latest transaction of its client a timestamped transaction can
+    /// be. Records within this tolerance are put back in timestamp order before being applied;
+    /// older ones are rejected, whether the latest transaction was applied or is still held
+    /// back. As this reorders the records of a client, it can't be combined with a journal.
+    pub timestamp_tolerance: Timestamp,
+    /// Number of accepted transactions during which disputes, resolves and chargebacks of
+    /// unknown transactions wait for them. Zero rejects them right away. Orphans can't wait with
+    /// several threads, which would count accepted transactions separately.
+    pub orphan_window: u64,
+    /// Resume from the ledger snapshot in this file, if it exists, and update it afterwards.
+    pub state: Option<PathBuf>,
+    /// Journal accepted transactions to this file, recovering from it first if a previous run
+    /// didn't complete.
+    pub journal: Option<PathBuf>,
+    /// Number of ledger threads transactions are spread across, by client. Zero or one process
+    /// them sequentially. Inputs with transfers, which span several clients, can't be processed
+    /// with several threads.
+    pub threads: usize,
+    /// Records buffered between the CSV reader and each ledger thread before the reader blocks,
+    /// which bounds memory use on large inputs. Defaults to [`DEFAULT_CHANNEL_CAPACITY`].
+    pub channel_capacity: Option<usize>,
+}
+
+impl Config {
+    /// Creates an empty ledger following the policies in this configuration.
+    pub fn ledger(&self) -> Ledger {
+        self.configure(Ledger::new())
+    }
+
+    /// Applies the policies in this configuration to `ledger`.
+    pub fn configure(&self, ledger: Ledger) -> Ledger {
+        ledger
+            .with_dispute_policy(self.dispute_policy)
+            .with_dispute_window(self.dispute_window)
+            .with_lock_policy(self.lock_policy)
+            .with_precision(self.precision)
+            .with_timestamp_tolerance(self.timestamp_tolerance)
+            .with_orphan_window(self.orphan_window)
+    }
+
+    /// Checks that the options of this configuration can be combined.
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.threads > 1 && self.orphan_window > 0 {
+            return Err(AppError::Config(
+                "an orphan window can't be combined with several threads",
+            ));
         }
-    };
+        if self.timestamp_tolerance > 0 && self.journal.is_some() {
+            return Err(AppError::Config(
+                "a timestamp tolerance can't be combined with a journal",
+            ));
+        }
+        if self.threads > 1 && self.dispute_window != DisputeWindow::Unbounded {
+            return Err(AppError::Config(
+                "a bounded dispute window can't be combined with several threads",
+            ));
+        }
+        Ok(())
+    }
 }
 
-fn process_transactions(
-    rx_channel: mpsc::Receiver<CsvTransactionRecord>,
+enum Input {
+    Record {
+        line: u64,
+        raw: csv::StringRecord,
+        record: CsvTransactionRecord,
+    },
+    Malformed {
+        line: u64,
+        raw: csv::StringRecord,
+        message: String,
+    },
+    /// A record rejected before reaching the ledger.
+    Rejected {
+        line: u64,
+        raw: csv::StringRecord,
+        error: TransactionError,
+    },
+}
+
+/// Where the ledger threads report the outcome of each transaction.
+struct Outputs {
     debug: bool,
+    rejects: Option<Mutex<RejectWriter<io::BufWriter<fs::File>>>>,
+    journal: Option<Mutex<Journal<fs::File>>>,
+}
+
+/// Applies the records received from the reader to `ledger`, the one of ledger thread `shard`,
+/// counting them in `progress` as they are handled.
+///
+/// Orphan operations still waiting for their transaction at the end of the input are expired,
+/// unless the ledger is saved to `config.state` where they can keep waiting.
+fn process_transactions(
+    rx_channel: mpsc::Receiver<Input>,
+    outputs: &Outputs,
     ledger: &mut Ledger,
-) {
-    while let Ok(record) = rx_channel.recv() {
-        let transaction_id = TransactionId(record.tx);
-        let amount = record.amount.unwrap_or_default();
+    config: &Config,
+    progress: &Progress,
+    shard: usize,
+) -> io::Result<()> {
+    let mut worker = Worker {
+        outputs,
+        ledger,
+        orphans: HashMap::new(),
+    };
+    while let Ok(input) = rx_channel.recv() {
+        match input {
+            Input::Record { line, raw, record } => worker.apply_record(line, raw, record)?,
+            Input::Malformed { line, raw, message } => {
+                if let Some(rejects) = outputs.rejects.as_ref() {
+                    rejects
+                        .lock()
+                        .unwrap()
+                        .write_malformed(line, &raw, message)?;
+                }
+            }
+            Input::Rejected { line, raw, error } => worker.report_error(line, &raw, &error)?,
+        }
+        progress.advance(shard);
+    }
+    if config.state.is_none() {
+        worker.ledger.expire_orphans();
+        worker.report_settled_orphans()?;
+    }
+    Ok(())
+}
+
+/// Inputs handled so far by each ledger thread, which the reader waits on.
+struct Progress {
+    handled: Vec<AtomicU64>,
+    /// Whether the reader is waiting, so that threads only notify it then.
+    waiting: AtomicBool,
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+impl Progress {
+    fn new(threads: usize) -> Progress {
+        Progress {
+            handled: (0..threads).map(|_| AtomicU64::new(0)).collect(),
+            waiting: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Counts one more input handled by thread `shard`.
+    fn advance(&self, shard: usize) {
+        self.handled[shard].fetch_add(1, Ordering::SeqCst);
+        self.notify();
+    }
+
+    /// Marks thread `shard` as stopped, so that the reader no longer waits on it.
+    fn stop(&self, shard: usize) {
+        self.handled[shard].store(u64::MAX, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        if self.waiting.load(Ordering::SeqCst) {
+            // Taking the lock makes sure the reader is either waiting or yet to check the count.
+            drop(self.lock.lock().unwrap());
+            self.changed.notify_all();
+        }
+    }
+
+    fn is_handled(&self, shard: usize, count: u64) -> bool {
+        self.handled[shard].load(Ordering::SeqCst) >= count
+    }
+
+    /// Blocks until thread `shard` handled `count` inputs or stopped.
+    fn wait(&self, shard: usize, count: u64) {
+        if self.is_handled(shard, count) {
+            return;
+        }
+        let mut guard = self.lock.lock().unwrap();
+        self.waiting.store(true, Ordering::SeqCst);
+        while !self.is_handled(shard, count) {
+            guard = self.changed.wait(guard).unwrap();
+        }
+        self.waiting.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Marks a ledger thread as stopped in [`Progress`] when dropped, even if the thread panics.
+struct Running<'a>(&'a Progress, usize);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.stop(self.1);
+    }
+}
+
+/// Hands the inputs read by the reader to the ledger threads.
+///
+/// Timestamped records are held back until a record at least `tolerance` later arrives, and
+/// sent in timestamp order, so that records slightly out of order in the input are applied in
+/// order. Records more than `tolerance` older than a record of their client still held back are
+/// rejected right away, as the ledger would once that record is applied. A record without a
+/// timestamp first releases all the records held back.
+///
+/// With several threads, a record naming a transaction id is only sent once the thread handling
+/// the previous record naming it is done with it, so that each thread sees the transactions of
+/// the others as a sequential run would.
+struct Dispatcher<'a> {
+    senders: Vec<mpsc::SyncSender<Input>>,
+    progress: &'a Progress,
+    tolerance: Timestamp,
+    /// Inputs sent to each thread so far.
+    sent: Vec<u64>,
+    /// Thread and position of the latest record sent for each transaction id. Entries of
+    /// records already handled are pruned once there are `limit` of them.
+    last: HashMap<TransactionId, (usize, u64)>,
+    limit: usize,
+    /// Records held back, by timestamp and line, with the thread they go to.
+    pending: BTreeMap<(Timestamp, u64), (usize, Input)>,
+    /// Latest timestamp read.
+    latest: Timestamp,
+    /// Latest timestamp and number of the records held back for each client.
+    held: HashMap<ClientId, (Timestamp, usize)>,
+}
+
+impl Dispatcher<'_> {
+    fn new(
+        senders: Vec<mpsc::SyncSender<Input>>,
+        progress: &Progress,
+        tolerance: Timestamp,
+        capacity: usize,
+    ) -> Dispatcher<'_> {
+        // Records not handled yet are at most those in the channels and one per thread, so
+        // pruning at twice that number at least halves the entries.
+        let limit = 2 * senders.len() * (capacity + 1);
+        Dispatcher {
+            sent: vec![0; senders.len()],
+            senders,
+            progress,
+            tolerance,
+            last: HashMap::new(),
+            limit,
+            pending: BTreeMap::new(),
+            latest: 0,
+            held: HashMap::new(),
+        }
+    }
+
+    /// Hands `input` to thread `shard`, or holds it back. Returns false if a ledger thread
+    /// stopped early, whose error is reported when joining it.
+    fn push(&mut self, shard: usize, input: Input) -> bool {
+        let Input::Record { line, raw, record } = input else {
+            return self.send(shard, input);
+        };
+        let Some(timestamp) = record.timestamp else {
+            return self.finish() && self.send(shard, Input::Record { line, raw, record });
+        };
         let client_id = ClientId(record.client);
-        let operation = Operation::from(record.tx_type);
-        process(
-            ledger,
-            transaction_id,
-            &Transaction::new(client_id, amount, operation),
-            debug,
-        )
+        let held = self.held.get(&client_id).map(|(latest, _)| *latest);
+        if held.is_some_and(|held| timestamp.saturating_add(self.tolerance) < held) {
+            let error = TransactionError::OutOfOrder(TransactionId(record.tx), timestamp);
+            return self.send(shard, Input::Rejected { line, raw, error });
+        }
+        let held = self.held.entry(client_id).or_insert((timestamp, 0));
+        *held = (held.0.max(timestamp), held.1 + 1);
+        self.latest = self.latest.max(timestamp);
+        let input = Input::Record { line, raw, record };
+        self.pending.insert((timestamp, line), (shard, input));
+        match self.latest.checked_sub(self.tolerance) {
+            Some(until) => self.release(until),
+            None => true,
+        }
+    }
+
+    /// Sends all the records held back.
+    fn finish(&mut self) -> bool {
+        self.release(Timestamp::MAX)
+    }
+
+    /// Sends the records held back with a timestamp up to `until`, in timestamp order.
+    fn release(&mut self, until: Timestamp) -> bool {
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > until {
+                break;
+            }
+            let (shard, input) = entry.remove();
+            if let Input::Record { record, .. } = &input {
+                let client_id = ClientId(record.client);
+                // Records are released in timestamp order, so the latest goes last.
+                let held = self.held.get_mut(&client_id).unwrap();
+                held.1 -= 1;
+                if held.1 == 0 {
+                    self.held.remove(&client_id);
+                }
+            }
+            if !self.send(shard, input) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn send(&mut self, shard: usize, input: Input) -> bool {
+        self.sent[shard] += 1;
+        match &input {
+            // Unlocks ignore their transaction id.
+            Input::Record { record, .. }
+                if self.senders.len() > 1 && record.tx_type != TransactionType::Unlock =>
+            {
+                let position = (shard, self.sent[shard]);
+                let previous = self.last.insert(TransactionId(record.tx), position);
+                if let Some((previous, count)) = previous.filter(|(other, _)| *other != shard) {
+                    self.progress.wait(previous, count);
+                }
+                if self.last.len() >= self.limit {
+                    let progress = self.progress;
+                    self.last
+                        .retain(|_, (shard, count)| !progress.is_handled(*shard, *count));
+                }
+            }
+            _ => {}
+        }
+        self.senders[shard].send(input).is_ok()
     }
 }
 
-pub fn process_file(filename: &String, debug: bool) -> Ledger {
-    let mut reader = create_reader(filename);
-    let (tx, rx) = mpsc::channel();
-    let handler = thread::spawn(move || {
-        let mut ledger = Ledger::new();
-        process_transactions(rx, debug, &mut ledger);
-        ledger
+/// Applies records to the ledger of a thread and reports their outcome.
+struct Worker<'a> {
+    outputs: &'a Outputs,
+    ledger: &'a mut Ledger,
+    /// Input line and record of the orphan operations parked in the ledger, by transaction id.
+    orphans: HashMap<TransactionId, VecDeque<(u64, csv::StringRecord)>>,
+}
+
+impl Worker<'_> {
+    fn apply_record(
+        &mut self,
+        line: u64,
+        raw: csv::StringRecord,
+        record: CsvTransactionRecord,
+    ) -> io::Result<()> {
+        let transaction_id = TransactionId(record.tx);
+        let result = match Transaction::try_from(record) {
+            Ok(transaction) => {
+                let transaction = transaction.with_reference(line);
+                self.journaled(line, transaction_id, &transaction)?
+                    .map(|()| transaction)
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(transaction) => {
+                if !transaction.operation().has_amount() && self.ledger.is_orphan(transaction_id) {
+                    let orphans = self.orphans.entry(transaction_id).or_default();
+                    orphans.push_back((line, raw));
+                }
+            }
+            Err(err) => self.report_error(line, &raw, &err)?,
+        }
+        self.report_settled_orphans()
+    }
+
+    /// Applies `transaction` to the ledger, journaling it first if there is a journal, so that
+    /// it can't be applied and then lost in a crash. The journal is held until the transaction
+    /// is applied, and its entry is marked as aborted if the ledger rejects it.
+    fn journaled(
+        &mut self,
+        line: u64,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+    ) -> io::Result<TransactionResult> {
+        let Some(journal) = self.outputs.journal.as_ref() else {
+            return Ok(self.ledger.apply_transaction(transaction_id, transaction));
+        };
+        let mut journal = journal.lock().unwrap();
+        journal.append(Some(line), transaction_id, transaction)?;
+        let result = self.ledger.apply_transaction(transaction_id, transaction);
+        if result.is_err() {
+            journal.abort()?;
+        }
+        Ok(result)
+    }
+
+    /// Reports the orphan operations that failed once their transaction arrived, or expired.
+    /// Those parked by an earlier run have no known input line, and are reported as line 0.
+    fn report_settled_orphans(&mut self) -> io::Result<()> {
+        for (transaction_id, _, result) in self.ledger.take_settled_orphans() {
+            let (line, raw) = self
+                .orphans
+                .get_mut(&transaction_id)
+                .and_then(VecDeque::pop_front)
+                .unwrap_or_default();
+            if let Err(err) = result {
+                self.report_error(line, &raw, &err)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report_error(
+        &self,
+        line: u64,
+        raw: &csv::StringRecord,
+        err: &TransactionError,
+    ) -> io::Result<()> {
+        if self.outputs.debug {
+            eprintln!("error: line {}: {}", line, error_chain(err));
+        }
+        if let Some(rejects) = self.outputs.rejects.as_ref() {
+            rejects.lock().unwrap().write_error(line, raw, err)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_record(
+    headers: &csv::StringRecord,
+    raw: &csv::StringRecord,
+) -> Result<CsvTransactionRecord, String> {
+    if raw.len() != headers.len() {
+        return Err(format!(
+            "expected {} fields, found {}",
+            headers.len(),
+            raw.len()
+        ));
+    }
+    raw.deserialize::<CsvTransactionRecord>(Some(headers))
+        .map_err(|err| match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+            _ => err.to_string(),
+        })
+}
+
+/// Reads the records of `reader` and hands each of them to `dispatcher` for the ledger thread
+/// handling its client, skipping the lines up to the one given for that client in `processed`.
+///
+/// Malformed records have no client and go to the first ledger thread. They are skipped if they
+/// come before the last line processed for any client. Each of them is reported on stderr,
+/// unless they are written to a rejects file, in which case only their number is.
+fn read_records<R: io::Read>(
+    reader: &mut csv::Reader<R>,
+    headers: &csv::StringRecord,
+    config: &Config,
+    processed: &BTreeMap<ClientId, u64>,
+    dispatcher: &mut Dispatcher,
+) -> Result<(), AppError> {
+    let threads = dispatcher.senders.len();
+    let skip_until = processed.values().copied().max().unwrap_or(0);
+    let mut malformed = 0;
+    for result in reader.records() {
+        let (position, raw, parsed) = match result {
+            Ok(raw) => {
+                let position = raw.position().cloned().unwrap_or_else(csv::Position::new);
+                let parsed = parse_record(headers, &raw);
+                (position, raw, parsed)
+            }
+            Err(err) => {
+                let position = err.position().cloned().unwrap_or_else(csv::Position::new);
+                if let csv::ErrorKind::Io(_) = err.kind() {
+                    return Err(AppError::Io(err.into()));
+                }
+                (position, csv::StringRecord::new(), Err(err.to_string()))
+            }
+        };
+        let line = position.line();
+        let (shard, input) = match parsed {
+            Ok(record) => {
+                let client_id = ClientId(record.client);
+                if line <= processed.get(&client_id).copied().unwrap_or(0) {
+                    continue;
+                }
+                let shard = Ledger::shard_of(client_id, threads);
+                (shard, Input::Record { line, raw, record })
+            }
+            Err(_) if line <= skip_until => continue,
+            Err(message) => {
+                if config.strict {
+                    return Err(AppError::MalformedRecord {
+                        line,
+                        byte: position.byte(),
+                        message,
+                    });
+                }
+                if config.rejects.is_none() {
+                    eprintln!(
+                        "warning: skipping malformed record at line {} (byte {}): {}",
+                        line,
+                        position.byte(),
+                        message
+                    );
+                }
+                malformed += 1;
+                (0, Input::Malformed { line, raw, message })
+            }
+        };
+        if !dispatcher.push(shard, input) {
+            break;
+        }
+    }
+    if let Some(rejects) = config.rejects.as_ref().filter(|_| malformed > 0) {
+        eprintln!(
+            "warning: skipped {} malformed records, reported in {}",
+            malformed,
+            rejects.display()
+        );
+    }
+    Ok(())
+}
+
+/// Processes CSV transactions read from any `io::Read` source, such as stdin, a socket or an
+/// in-memory buffer.
+///
+/// Records that can't be parsed are reported, on stderr or in `config.rejects`, and skipped,
+/// unless `config.strict` is set, in which case processing stops at the first of them.
+pub fn process_reader<R: io::Read>(input: R, config: &Config) -> Result<Ledger, AppError> {
+    resume_reader(config.ledger(), input, config)
+}
+
+/// Like [`process_reader`], but applies the transactions on top of an existing `ledger`.
+///
+/// With `config.threads` above one, the ledger is split by client across that many threads,
+/// each applying the transactions of its clients in input order, and merged back at the end.
+/// Records naming the same transaction id are applied in input order across threads, so the
+/// outcome of each transaction matches a sequential run, but rejects are reported in the order
+/// threads process them rather than in input order. Inputs with a `to` column, whose transfers
+/// can involve clients of different threads, are rejected as an invalid configuration.
+pub fn resume_reader<R: io::Read>(
+    mut ledger: Ledger,
+    input: R,
+    config: &Config,
+) -> Result<Ledger, AppError> {
+    config.validate()?;
+    let mut reader = create_reader(input);
+    let headers = reader.headers().map_err(io::Error::from)?.clone();
+    check_headers(&headers)?;
+    // Transfers move funds between clients that may be handled by different threads, which
+    // can't see each other's accounts.
+    if config.threads > 1 && headers.iter().any(|header| header == "to") {
+        return Err(AppError::Config(
+            "inputs with transfers can't be processed with several threads",
+        ));
+    }
+    let rejects = match config.rejects.as_ref() {
+        Some(path) => {
+            let file = fs::File::create(path)?;
+            Some(Mutex::new(RejectWriter::new(io::BufWriter::new(file))))
+        }
+        None => None,
+    };
+    let mut processed = BTreeMap::new();
+    let journal = match config.journal.as_ref() {
+        Some(path) => {
+            let (journal, recovery) = open_journal(path, &mut ledger)?;
+            if let Some(line) = recovery.lines.values().max() {
+                eprintln!("warning: recovered journal, resuming input after line {line}");
+                processed = recovery.lines;
+            }
+            Some(Mutex::new(journal))
+        }
+        None => None,
+    };
+    let outputs = Outputs {
+        debug: config.debug,
+        rejects,
+        journal,
+    };
+    let mut ledgers = ledger.split(config.threads.max(1));
+    let capacity = config.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY);
+    let progress = Progress::new(ledgers.len());
+    let (result, applied) = thread::scope(|scope| {
+        let mut senders = Vec::with_capacity(ledgers.len());
+        let mut handlers = Vec::with_capacity(ledgers.len());
+        for (shard, ledger) in ledgers.iter_mut().enumerate() {
+            let (tx, rx) = mpsc::sync_channel(capacity);
+            let (outputs, progress) = (&outputs, &progress);
+            senders.push(tx);
+            handlers.push(scope.spawn(move || {
+                let _running = Running(progress, shard);
+                process_transactions(rx, outputs, ledger, config, progress, shard)
+            }));
+        }
+        let tolerance = config.timestamp_tolerance;
+        let mut dispatcher = Dispatcher::new(senders, &progress, tolerance, capacity);
+        let result = read_records(&mut reader, &headers, config, &processed, &mut dispatcher);
+        dispatcher.finish();
+        drop(dispatcher);
+        let applied: io::Result<()> = handlers
+            .into_iter()
+            .try_for_each(|handler| handler.join().unwrap());
+        (result, applied)
     });
-    for record in reader.deserialize::<CsvTransactionRecord>().flatten() {
-        let _ = tx.send(record);
+    let flushed = match outputs.rejects {
+        Some(rejects) => rejects.into_inner().unwrap().flush(),
+        None => Ok(()),
+    };
+    applied?;
+    flushed?;
+    let mut ledgers = ledgers.into_iter();
+    let mut ledger = ledgers.next().unwrap();
+    for shard in ledgers {
+        ledger.merge(shard);
+    }
+    result.map(|()| ledger)
+}
+
+/// Opens the journal at `path` for appending, after replaying on `ledger` whatever it holds from
+/// a previous run that didn't complete.
+fn open_journal(
+    path: &Path,
+    ledger: &mut Ledger,
+) -> Result<(Journal<fs::File>, Recovery), AppError> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let recovery = journal::replay(ledger, io::BufReader::new(&file)).map_err(AppError::Journal)?;
+    // Orphans settled while replaying were already reported by the interrupted run.
+    ledger.take_settled_orphans();
+    file.set_len(recovery.length)?;
+    file.seek(io::SeekFrom::End(0))?;
+    let sequence = ledger.sequence();
+    Ok((Journal::new(file, sequence), recovery))
+}
+
+/// Saves the state of `ledger` when `config.state` is set and then clears the journal, whose
+/// entries are all part of the completed run.
+pub fn checkpoint(ledger: &Ledger, config: &Config) -> Result<(), AppError> {
+    if let Some(path) = config.state.as_ref() {
+        save_state(ledger, path)?;
+    }
+    if let Some(path) = config.journal.as_ref() {
+        fs::File::create(path)?;
     }
-    drop(tx);
-    handler.join().unwrap()
+    Ok(())
+}
+
+pub fn process_file(filename: &String, config: &Config) -> Result<Ledger, AppError> {
+    let file = fs::File::open(filename)?;
+    process_reader(file, config)
+}
+
+/// Loads the ledger snapshot at `path`, or creates an empty ledger if there is none yet.
+pub fn load_state(path: &Path, config: &Config) -> Result<Ledger, AppError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(config.ledger()),
+        Err(err) => return Err(AppError::Io(err)),
+    };
+    let ledger = Ledger::load(io::BufReader::new(file)).map_err(AppError::State)?;
+    Ok(config.configure(ledger))
+}
+
+/// Writes a snapshot of `ledger` to `path`. The snapshot is written to a temporary file first and
+/// then renamed, so an interrupted write never leaves a truncated snapshot behind.
+pub fn save_state(ledger: &Ledger, path: &Path) -> Result<(), AppError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let file = fs::File::create(&temporary)?;
+    let mut writer = io::BufWriter::new(file);
+    ledger.save(&mut writer).map_err(AppError::State)?;
+    let file = writer.into_inner().map_err(io::Error::from)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Order in which accounts are written to the output.
+#[derive(Copy, Clone, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum SortOrder {
+    /// Ascending client id.
+    #[default]
+    Client,
+    /// Descending total funds, then ascending client id.
+    Total,
+    /// Descending available funds, then ascending client id.
+    Available,
+    /// Locked accounts first, then ascending client id.
+    LockedFirst,
 }
 
-pub fn app(filename: &String, debug: bool) {
-    let ledger = process_file(filename, debug);
-    let mut writer = csv::WriterBuilder::new().from_writer(io::BufWriter::new(io::stdout()));
-    for (key, account) in ledger {
+/// Writes the accounts in `ledger` as CSV to `output`, in the given order, with a row per
+/// currency of each account.
+///
+/// The `currency` column is only written when some account holds funds in a currency other than
+/// [`Currency::NONE`], so ledgers fed with single-currency input keep their original format.
+pub fn write_accounts<W: io::Write>(
+    ledger: &Ledger,
+    order: SortOrder,
+    output: W,
+) -> csv::Result<()> {
+    let mut rows: Vec<(ClientId, Currency, Balance, bool)> = Vec::new();
+    for (key, account) in ledger.accounts() {
+        let first = rows.len();
+        for (currency, balance) in account.balances() {
+            rows.push((*key, currency, balance, account.locked()));
+        }
+        if rows.len() == first {
+            rows.push((*key, Currency::NONE, Balance::default(), account.locked()));
+        }
+    }
+    match order {
+        SortOrder::Client => {}
+        SortOrder::Total => rows.sort_by_key(|(_, _, balance, _)| Reverse(balance.total())),
+        SortOrder::Available => rows.sort_by_key(|(_, _, balance, _)| Reverse(balance.available())),
+        SortOrder::LockedFirst => rows.sort_by_key(|(_, _, _, locked)| !locked),
+    }
+    let multi_currency = rows
+        .iter()
+        .any(|(_, currency, _, _)| *currency != Currency::NONE);
+    let mut writer = csv::WriterBuilder::new().from_writer(output);
+    for (key, currency, balance, locked) in rows {
         let val = CsvAccountRecord {
             client: key.0,
-            available: format!("{:.4}", account.available()),
-            held: format!("{:.4}", account.held()),
-            total: format!("{:.4}", account.total()),
-            locked: account.locked(),
+            currency: multi_currency.then_some(currency),
+            available: format!("{:.4}", balance.available()),
+            held: format!("{:.4}", balance.held()),
+            total: format!("{:.4}", balance.total()),
+            locked,
         };
-        let _ = writer.serialize(val);
+        writer.serialize(val)?;
     }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Runs the application on `filename`, reading from stdin when `filename` is `-`.
+pub fn app(filename: &String, config: &Config) -> Result<(), AppError> {
+    let ledger = match config.state.as_ref() {
+        Some(path) => load_state(path, config)?,
+        None => config.ledger(),
+    };
+    let ledger = if filename == "-" {
+        resume_reader(ledger, io::stdin().lock(), config)?
+    } else {
+        resume_reader(ledger, fs::File::open(filename)?, config)?
+    };
+    checkpoint(&ledger, config)?;
+    let _ = write_accounts(&ledger, config.sort, io::BufWriter::new(io::stdout()));
+    Ok(())
 }
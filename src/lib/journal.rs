@@ -0,0 +1,451 @@
+use std::{collections::BTreeMap, error, fmt, fs, io};
+
+use super::account::ClientId;
+use super::ledger::Ledger;
+use super::transactions::{Transaction, TransactionError, TransactionId};
+
+/// A transaction passed to the ledger, as recorded in the journal before it is applied.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JournalEntry {
+    /// Ledger sequence number after the transaction is applied.
+    pub sequence: u64,
+    /// Input line the transaction was read from, if it came from a CSV file.
+    pub line: Option<u64>,
+    pub transaction_id: TransactionId,
+    pub transaction: Transaction,
+}
+
+/// A line of the journal: the entry of a transaction, or the marker of an entry whose
+/// transaction the ledger rejected.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Entry(Box<JournalEntry>),
+    Aborted { aborted: u64 },
+}
+
+/// Output a journal can force to durable storage.
+pub trait SyncWrite: io::Write {
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl SyncWrite for fs::File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        fs::File::sync_data(self)
+    }
+}
+
+/// In-memory journals have nothing to sync.
+impl SyncWrite for Vec<u8> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// An entry before the end of the journal doesn't match its checksum.
+    Corrupted {
+        line: usize,
+    },
+    /// A journaled transaction was rejected without being marked as aborted, or accepted with
+    /// an unexpected sequence number or despite being marked as aborted, when replayed on the
+    /// ledger.
+    Diverged {
+        sequence: u64,
+        error: Option<TransactionError>,
+    },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(err) => write!(f, "{}", err),
+            JournalError::Corrupted { line } => write!(f, "corrupted journal entry at line {line}"),
+            JournalError::Diverged { sequence, .. } => {
+                write!(f, "ledger diverged from journal at sequence {sequence}")
+            }
+        }
+    }
+}
+
+impl error::Error for JournalError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            JournalError::Io(err) => Some(err),
+            JournalError::Diverged {
+                error: Some(err), ..
+            } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(value: io::Error) -> Self {
+        JournalError::Io(value)
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Append-only write-ahead log of the transactions of a ledger.
+///
+/// Each entry is a line holding the CRC-32 of its payload followed by the payload itself, a JSON
+/// encoded [`JournalEntry`]. Entries are synced to disk before their transaction is applied, so
+/// that a transaction is never applied without being journaled. Entries of transactions the
+/// ledger then rejects are followed by an abort marker, a line of the same form whose payload
+/// names the sequence number of the entry.
+///
+/// Entries are numbered by the journal itself, so that transactions accepted by several ledgers
+/// working on disjoint sets of clients can share a journal and be replayed on their merge. Each
+/// of them must then hold the journal from appending an entry until it is applied or aborted.
+pub struct Journal<W: SyncWrite> {
+    output: W,
+    sequence: u64,
+}
+
+impl<W: SyncWrite> Journal<W> {
+    /// Creates a journal whose next entry follows the ledger sequence number `sequence`.
+    pub fn new(output: W, sequence: u64) -> Self {
+        Self { output, sequence }
+    }
+
+    /// Sequence number of the last entry appended.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Journals a transaction about to be applied, syncing it to disk. If the ledger rejects
+    /// it, [`Journal::abort`] must be called before appending anything else.
+    pub fn append(
+        &mut self,
+        line: Option<u64>,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+    ) -> io::Result<()> {
+        let entry = JournalEntry {
+            sequence: self.sequence + 1,
+            line,
+            transaction_id,
+            transaction: *transaction,
+        };
+        let sequence = entry.sequence;
+        self.write(&Record::Entry(Box::new(entry)))?;
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    /// Marks the last entry appended as aborted, as the ledger rejected its transaction.
+    pub fn abort(&mut self) -> io::Result<()> {
+        self.write(&Record::Aborted {
+            aborted: self.sequence,
+        })?;
+        self.sequence -= 1;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let payload = serde_json::to_string(record)?;
+        writeln!(
+            self.output,
+            "{:08x} {}",
+            checksum(payload.as_bytes()),
+            payload
+        )?;
+        self.output.flush()?;
+        self.output.sync_data()
+    }
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let (crc, payload) = line.split_once(' ')?;
+    let crc = u32::from_str_radix(crc, 16).ok()?;
+    if crc != checksum(payload.as_bytes()) {
+        return None;
+    }
+    serde_json::from_str(payload).ok()
+}
+
+/// Outcome of replaying the last entry read from a journal, when it may be followed by an abort
+/// marker.
+enum Replayed {
+    /// The entry was skipped, as the ledger already contains its sequence number.
+    Skipped(u64),
+    /// The ledger rejected the entry, which must be marked as aborted. Its position in the
+    /// journal is kept, to drop it if the marker is missing.
+    Rejected(Box<JournalEntry>, TransactionError, u64),
+}
+
+/// Outcome of replaying a journal.
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovery {
+    /// Last valid entry of the journal that wasn't aborted.
+    pub last: Option<JournalEntry>,
+    /// Length in bytes of the valid part of the journal. Anything past it is the remains of an
+    /// interrupted write and should be truncated before appending new entries.
+    pub length: u64,
+    /// Last input line journaled for each client, aborted entries included. Transactions of a
+    /// client are processed in input order, so every line of that client up to this one was
+    /// already processed.
+    pub lines: BTreeMap<ClientId, u64>,
+}
+
+/// Rebuilds the state of `ledger` by applying the entries of a journal that it doesn't contain
+/// yet, that is, those with a sequence number past [`Ledger::sequence`].
+///
+/// Entries marked as aborted are skipped, once checked that the ledger rejects them again.
+///
+/// A damaged last entry is the trace of a write interrupted by a crash; it was never
+/// acknowledged, so it is ignored. So is a last entry that the ledger rejects without it being
+/// marked as aborted, as the process died before marking it. Damaged entries anywhere else are
+/// reported as corruption.
+pub fn replay<R: io::BufRead>(ledger: &mut Ledger, mut input: R) -> Result<Recovery, JournalError> {
+    let mut recovery = Recovery::default();
+    let mut damaged = None;
+    let mut replayed = None;
+    let mut line = String::new();
+    let mut index = 0;
+    loop {
+        line.clear();
+        let length = input.read_line(&mut line)?;
+        if length == 0 {
+            break;
+        }
+        index += 1;
+        if let Some(line) = damaged {
+            return Err(JournalError::Corrupted { line });
+        }
+        let record = match line.strip_suffix('\n').and_then(parse_record) {
+            Some(record) => record,
+            None => {
+                damaged = Some(index);
+                continue;
+            }
+        };
+        let entry = match (record, replayed.take()) {
+            (Record::Aborted { aborted }, Some(Replayed::Rejected(entry, _, _)))
+                if entry.sequence == aborted =>
+            {
+                if let Some(line) = entry.line {
+                    recovery.lines.insert(entry.transaction.client_id(), line);
+                }
+                recovery.length += length as u64;
+                continue;
+            }
+            (Record::Aborted { aborted }, Some(Replayed::Skipped(sequence)))
+                if sequence == aborted =>
+            {
+                recovery.length += length as u64;
+                continue;
+            }
+            (Record::Aborted { aborted }, _) => {
+                return Err(JournalError::Diverged {
+                    sequence: aborted,
+                    error: None,
+                })
+            }
+            (Record::Entry(_), Some(Replayed::Rejected(entry, error, _))) => {
+                return Err(JournalError::Diverged {
+                    sequence: entry.sequence,
+                    error: Some(error),
+                })
+            }
+            (Record::Entry(entry), _) => entry,
+        };
+        if entry.sequence <= ledger.sequence() {
+            replayed = Some(Replayed::Skipped(entry.sequence));
+        } else if let Err(error) =
+            ledger.apply_transaction(entry.transaction_id, &entry.transaction)
+        {
+            replayed = Some(Replayed::Rejected(entry, error, recovery.length));
+            recovery.length += length as u64;
+            continue;
+        } else if ledger.sequence() != entry.sequence {
+            return Err(JournalError::Diverged {
+                sequence: entry.sequence,
+                error: None,
+            });
+        }
+        if let Some(line) = entry.line {
+            recovery.lines.insert(entry.transaction.client_id(), line);
+        }
+        recovery.last = Some(*entry);
+        recovery.length += length as u64;
+    }
+    if let Some(Replayed::Rejected(_, _, length)) = replayed {
+        recovery.length = length;
+    }
+    Ok(recovery)
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::{replay, Journal, JournalError};
+    use crate::account::{num, ClientId};
+    use crate::ledger::Ledger;
+    use crate::transactions::{Operation, Transaction, TransactionId};
+
+    fn journal_of(ledger: &mut Ledger, transactions: &[(TransactionId, Transaction)]) -> Vec<u8> {
+        let mut journal = Journal::new(Vec::new(), ledger.sequence());
+        for (transaction_id, transaction) in transactions {
+            journal.append(None, *transaction_id, transaction).unwrap();
+            if ledger
+                .apply_transaction(*transaction_id, transaction)
+                .is_err()
+            {
+                journal.abort().unwrap();
+            }
+        }
+        journal.output
+    }
+
+    fn transactions() -> Vec<(TransactionId, Transaction)> {
+        vec![
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), num!(10.0), Operation::Deposit),
+            ),
+            (
+                TransactionId(2),
+                Transaction::new(ClientId(1), num!(20.0), Operation::Withdrawal),
+            ),
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), num!(0.0), Operation::Dispute),
+            ),
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), num!(0.0), Operation::Resolve),
+            ),
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), num!(0.0), Operation::Dispute),
+            ),
+        ]
+    }
+
+    #[test]
+    fn replay_rebuilds_ledger() {
+        let mut ledger = Ledger::new();
+        let journal = journal_of(&mut ledger, &transactions());
+        // The withdrawal was rejected, and its entry aborted.
+        let aborted = String::from_utf8_lossy(&journal).matches("aborted").count();
+        assert_eq!(aborted, 1);
+        let mut recovered = Ledger::new();
+        let recovery = replay(&mut recovered, journal.as_slice()).unwrap();
+        assert_eq!(recovery.length, journal.len() as u64);
+        assert_eq!(recovery.last.unwrap().sequence, 4);
+        assert_eq!(recovered.sequence(), ledger.sequence());
+        assert_eq!(
+            recovered.accounts().collect::<Vec<_>>(),
+            ledger.accounts().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn replay_skips_entries_already_in_ledger() {
+        let transactions = transactions();
+        let mut ledger = Ledger::new();
+        let journal = journal_of(&mut ledger, &transactions);
+        let mut recovered = Ledger::new();
+        for (transaction_id, transaction) in &transactions[..3] {
+            let _ = recovered.apply_transaction(*transaction_id, transaction);
+        }
+        assert_eq!(recovered.sequence(), 2);
+        replay(&mut recovered, journal.as_slice()).unwrap();
+        assert_eq!(recovered.sequence(), ledger.sequence());
+        assert_eq!(
+            recovered.accounts().collect::<Vec<_>>(),
+            ledger.accounts().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn replay_ignores_interrupted_last_entry() {
+        let mut ledger = Ledger::new();
+        let mut journal = journal_of(&mut ledger, &transactions());
+        let length = journal.len() as u64;
+        journal.extend_from_slice(b"0badc0de {\"sequence\":5,");
+        let mut recovered = Ledger::new();
+        let recovery = replay(&mut recovered, journal.as_slice()).unwrap();
+        assert_eq!(recovery.length, length);
+        assert_eq!(recovered.sequence(), 4);
+    }
+
+    #[test]
+    fn replay_applies_entry_journaled_before_crash() {
+        let mut ledger = Ledger::new();
+        let transactions = transactions();
+        let mut journal = Journal::new(Vec::new(), 0);
+        journal.output = journal_of(&mut ledger, &transactions[..1]);
+        journal.sequence = ledger.sequence();
+        // The process dies after journaling the second deposit, before applying it.
+        let deposit = Transaction::new(ClientId(1), num!(5.0), Operation::Deposit);
+        journal.append(None, TransactionId(3), &deposit).unwrap();
+        let mut recovered = Ledger::new();
+        let recovery = replay(&mut recovered, journal.output.as_slice()).unwrap();
+        assert_eq!(recovery.length, journal.output.len() as u64);
+        assert_eq!(recovered.sequence(), 2);
+        let (_, account) = recovered.accounts().next().unwrap();
+        assert_eq!(account.available(), num!(15.0));
+    }
+
+    #[test]
+    fn replay_drops_rejected_last_entry_without_abort_marker() {
+        let mut ledger = Ledger::new();
+        let transactions = transactions();
+        let mut journal = Journal::new(Vec::new(), 0);
+        journal.output = journal_of(&mut ledger, &transactions[..1]);
+        journal.sequence = ledger.sequence();
+        let length = journal.output.len() as u64;
+        // The process dies after the ledger rejects the withdrawal, before marking it aborted.
+        let (transaction_id, withdrawal) = transactions[1];
+        journal.append(None, transaction_id, &withdrawal).unwrap();
+        let mut recovered = Ledger::new();
+        let recovery = replay(&mut recovered, journal.output.as_slice()).unwrap();
+        assert_eq!(recovery.length, length);
+        assert_eq!(recovered.sequence(), 1);
+    }
+
+    #[test]
+    fn replay_reports_aborted_entry_the_ledger_accepts() {
+        let mut journal = Journal::new(Vec::new(), 0);
+        let (transaction_id, deposit) = transactions()[0];
+        journal.append(None, transaction_id, &deposit).unwrap();
+        journal.abort().unwrap();
+        let mut recovered = Ledger::new();
+        let res = replay(&mut recovered, journal.output.as_slice());
+        assert!(matches!(
+            res,
+            Err(JournalError::Diverged {
+                sequence: 1,
+                error: None
+            })
+        ));
+    }
+
+    #[test]
+    fn replay_reports_corrupted_entry() {
+        let mut ledger = Ledger::new();
+        let mut journal = journal_of(&mut ledger, &transactions());
+        // Flip a digit of the first entry's amount.
+        let position = journal.windows(4).position(|w| w == b"\"10.").unwrap();
+        journal[position + 1] = b'9';
+        let mut recovered = Ledger::new();
+        let res = replay(&mut recovered, journal.as_slice());
+        assert!(matches!(res, Err(JournalError::Corrupted { line: 1 })));
+    }
+}
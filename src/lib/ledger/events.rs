@@ -0,0 +1,74 @@
+use crate::account::{Balance, ClientId, Currency};
+use crate::transactions::{Operation, Transaction, TransactionError, TransactionId};
+
+/// Balance of an account in one currency, before and after a transaction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BalanceChange {
+    pub client_id: ClientId,
+    pub currency: Currency,
+    pub before: Balance,
+    pub after: Balance,
+}
+
+/// A transaction applied to a ledger, with the balances of the accounts it touched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Applied {
+    pub transaction_id: TransactionId,
+    pub transaction: Transaction,
+    /// Balances of the client of the transaction and, for transfers and their disputes, of the
+    /// other client involved.
+    pub changes: Vec<BalanceChange>,
+}
+
+/// A change of the state of a ledger, as seen by a [`LedgerObserver`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LedgerEvent {
+    Deposited(Applied),
+    Withdrawn(Applied),
+    Transferred(Applied),
+    Disputed(Applied),
+    Resolved(Applied),
+    ChargedBack(Applied),
+    Represented(Applied),
+    PreArbitrated(Applied),
+    /// An account was locked, after the event of the chargeback that locked it.
+    AccountLocked(ClientId),
+    AccountUnlocked(ClientId),
+    /// An operation kept until its transaction arrives, see [`super::Ledger::with_orphan_window`].
+    Parked {
+        transaction_id: TransactionId,
+        transaction: Transaction,
+    },
+    Rejected {
+        transaction_id: TransactionId,
+        transaction: Transaction,
+        error: TransactionError,
+    },
+}
+
+impl LedgerEvent {
+    /// Event of an applied transaction. Unlocks only surface as [`LedgerEvent::AccountUnlocked`].
+    pub(super) fn applied(applied: Applied) -> Option<LedgerEvent> {
+        let event = match applied.transaction.operation() {
+            Operation::Deposit => LedgerEvent::Deposited,
+            Operation::Withdrawal => LedgerEvent::Withdrawn,
+            Operation::Transfer => LedgerEvent::Transferred,
+            Operation::Dispute => LedgerEvent::Disputed,
+            Operation::Resolve => LedgerEvent::Resolved,
+            Operation::Chargeback => LedgerEvent::ChargedBack,
+            Operation::Representment => LedgerEvent::Represented,
+            Operation::PreArbitration => LedgerEvent::PreArbitrated,
+            Operation::Unlock => return None,
+        };
+        Some(event(applied))
+    }
+}
+
+/// Receives the events of a ledger as its transactions are applied, to drive notifications,
+/// audit logs or projections.
+///
+/// Observers are shared with the shards of a split ledger, so they may be called from several
+/// threads at once.
+pub trait LedgerObserver: Send + Sync {
+    fn on_event(&self, event: &LedgerEvent);
+}
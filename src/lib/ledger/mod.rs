@@ -1,17 +1,108 @@
 use super::{
-    account::Account, account::ClientId, account::Number, transactions::Operation,
+    account::Account, account::ClientId, account::Currency, account::Number, account::Timestamp,
+    transactions::DisputeHistory, transactions::DisputePolicy, transactions::DisputeWindow,
+    transactions::LockPolicy, transactions::Operation, transactions::Precision,
     transactions::Transaction, transactions::TransactionError, transactions::TransactionId,
     transactions::TransactionResult, transactions::TransactionState,
 };
 
-use std::collections::HashMap;
+use std::collections::{hash_map, BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
 
-type AccountMap = HashMap<ClientId, Account>;
+mod events;
+mod ranges;
+mod store;
+pub use events::{Applied, BalanceChange, LedgerEvent, LedgerObserver};
+use ranges::IdRanges;
+pub use store::{AccountStore, DenseAccountStore, FileTransactionStore, TransactionStore};
+
+type AccountMap = BTreeMap<ClientId, Account>;
 type TransactionMap = HashMap<TransactionId, Transaction>;
+/// Client and operation of the transactions claimed by any of the shards of a split ledger, by
+/// transaction id.
+type SharedIds = Arc<Mutex<HashMap<TransactionId, (ClientId, Operation)>>>;
+
+/// Change made to a ledger while applying a batch, recorded so that it can be undone.
+enum Undo {
+    Account(ClientId, Option<Account>),
+    Transaction(TransactionId, Option<Transaction>),
+    Evicted(TransactionId),
+    WindowPushedBack,
+    WindowPoppedFront(TransactionId),
+    WindowPushedFront,
+    Claimed(TransactionId),
+    Orphans(VecDeque<Orphan>),
+    History(TransactionId, Option<DisputeHistory>),
+}
+
+/// A dispute, resolve or chargeback waiting for the transaction it refers to.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Orphan {
+    transaction_id: TransactionId,
+    transaction: Transaction,
+    /// Ledger sequence number past which it expires.
+    deadline: u64,
+}
+
+/// Outcome of an orphan operation, once its transaction arrived or it expired.
+pub type SettledOrphan = (TransactionId, Transaction, TransactionResult);
 
-pub struct Ledger {
-    accounts: AccountMap,
-    transactions: TransactionMap,
+/// The ledger state is made of its accounts and transactions. Policies are configuration rather
+/// than state, so they are not part of snapshots and must be set again after loading one.
+///
+/// Transactions and accounts are kept in maps by default. Other stores can be used with
+/// [`Ledger::with_stores`], such as a [`FileTransactionStore`] for histories that don't fit in
+/// memory or a [`DenseAccountStore`] for faster account lookups. Snapshots and sharded
+/// processing are only available with the default stores.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Ledger<T = TransactionMap, A = AccountMap> {
+    accounts: A,
+    transactions: T,
+    #[serde(default)]
+    sequence: u64,
+    /// Deposits and withdrawals in the dispute window, oldest first. Only kept when the window
+    /// is bounded.
+    #[serde(default)]
+    window: VecDeque<TransactionId>,
+    /// Transactions evicted past the dispute window, so that they are still told apart from
+    /// unknown ones and their ids can't be reused.
+    #[serde(default)]
+    evicted: IdRanges,
+    /// Orphan operations, oldest first.
+    #[serde(default)]
+    orphans: VecDeque<Orphan>,
+    /// Dispute histories of the transactions that were disputed.
+    #[serde(default)]
+    histories: HashMap<TransactionId, DisputeHistory>,
+    #[serde(skip)]
+    dispute_policy: DisputePolicy,
+    #[serde(skip)]
+    dispute_window: DisputeWindow,
+    #[serde(skip)]
+    lock_policy: LockPolicy,
+    #[serde(skip)]
+    precision: Precision,
+    #[serde(skip)]
+    timestamp_tolerance: Timestamp,
+    #[serde(skip)]
+    orphan_window: u64,
+    /// Orphan operations settled since they were last taken.
+    #[serde(skip)]
+    settled: Vec<SettledOrphan>,
+    #[serde(skip)]
+    shared_ids: Option<SharedIds>,
+    /// Index of this ledger among the shards of a split ledger, and their number.
+    #[serde(skip)]
+    shard: Option<(usize, usize)>,
+    /// Changes made by the batch being applied, if any.
+    #[serde(skip)]
+    undo: Option<Vec<Undo>>,
+    #[serde(skip)]
+    observer: Option<Arc<dyn LedgerObserver>>,
+    /// Events of the batch being applied, only emitted once it succeeds.
+    #[serde(skip)]
+    pending: Vec<LedgerEvent>,
 }
 
 impl Default for Ledger {
@@ -22,42 +113,813 @@ impl Default for Ledger {
 
 impl Ledger {
     pub fn new() -> Ledger {
+        Ledger::with_store(TransactionMap::with_capacity(128))
+    }
+
+    /// Shard holding the accounts and transactions of `client_id` in a ledger split in `shards`.
+    pub fn shard_of(client_id: ClientId, shards: usize) -> usize {
+        client_id.0 as usize % shards
+    }
+
+    /// Splits this ledger into `shards` ledgers holding the accounts and transactions of disjoint
+    /// sets of clients, as given by [`Ledger::shard_of`], so they can be processed in parallel.
+    ///
+    /// Transaction ids stay unique across all shards, and disputes naming a transaction of
+    /// another shard are rejected as they would be by this ledger. The first shard carries the
+    /// sequence number of this ledger, so that merging the shards back adds up their sequences.
+    ///
+    /// A bounded dispute window spans the transactions of all clients, so a ledger with one
+    /// isn't split, and is returned as the only shard.
+    pub fn split(self, shards: usize) -> Vec<Ledger> {
+        if shards <= 1 || self.dispute_window != DisputeWindow::Unbounded {
+            return vec![self];
+        }
+        // Evicted ids are copied to every shard, which rejects them on its own.
+        let claims = self
+            .transactions
+            .iter()
+            .map(|(transaction_id, transaction)| {
+                let owner = (transaction.client_id(), transaction.operation());
+                (*transaction_id, owner)
+            });
+        let shared_ids: SharedIds = Arc::new(Mutex::new(claims.collect()));
+        let mut ledgers: Vec<Ledger> = (0..shards)
+            .map(|_| Ledger {
+                evicted: self.evicted.clone(),
+                shared_ids: Some(shared_ids.clone()),
+                observer: self.observer.clone(),
+                ..Ledger::new()
+                    .with_dispute_policy(self.dispute_policy)
+                    .with_dispute_window(self.dispute_window)
+                    .with_lock_policy(self.lock_policy)
+                    .with_precision(self.precision)
+                    .with_timestamp_tolerance(self.timestamp_tolerance)
+                    .with_orphan_window(self.orphan_window)
+            })
+            .collect();
+        for (index, ledger) in ledgers.iter_mut().enumerate() {
+            ledger.shard = Some((index, shards));
+        }
+        ledgers[0].sequence = self.sequence;
+        for (client_id, account) in self.accounts {
+            ledgers[Ledger::shard_of(client_id, shards)]
+                .accounts
+                .insert(client_id, account);
+        }
+        for (transaction_id, history) in self.histories {
+            let client_id = self.transactions[&transaction_id].client_id();
+            ledgers[Ledger::shard_of(client_id, shards)]
+                .histories
+                .insert(transaction_id, history);
+        }
+        for transaction_id in self.window {
+            let client_id = self.transactions[&transaction_id].client_id();
+            ledgers[Ledger::shard_of(client_id, shards)]
+                .window
+                .push_back(transaction_id);
+        }
+        for (transaction_id, transaction) in self.transactions {
+            ledgers[Ledger::shard_of(transaction.client_id(), shards)]
+                .transactions
+                .insert(transaction_id, transaction);
+        }
+        for mut orphan in self.orphans {
+            let shard = Ledger::shard_of(orphan.transaction.client_id(), shards);
+            // Only the first shard carries the sequence number of this ledger.
+            if shard != 0 {
+                orphan.deadline = orphan.deadline.saturating_sub(self.sequence);
+            }
+            ledgers[shard].orphans.push_back(orphan);
+        }
+        ledgers
+    }
+
+    /// Merges back a ledger produced by [`Ledger::split`] into this one.
+    pub fn merge(&mut self, other: Ledger) {
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.histories.extend(other.histories);
+        // Deadlines of orphans are relative to the sequence number of their own ledger.
+        for orphan in self.orphans.iter_mut() {
+            orphan.deadline += other.sequence;
+        }
+        for mut orphan in other.orphans {
+            orphan.deadline += self.sequence;
+            self.orphans.push_back(orphan);
+        }
+        self.orphans
+            .make_contiguous()
+            .sort_by_key(|orphan| orphan.deadline);
+        self.sequence += other.sequence;
+        self.window.extend(other.window);
+        self.evicted.extend(other.evicted);
+        self.shared_ids = None;
+        self.shard = None;
+    }
+
+    /// Writes a snapshot of the accounts and transactions of this ledger to `output`.
+    pub fn save<W: io::Write>(&self, output: W) -> serde_json::Result<()> {
+        serde_json::to_writer(output, self)
+    }
+
+    /// Restores a ledger from a snapshot written by [`Ledger::save`], with default policies.
+    pub fn load<R: io::Read>(input: R) -> serde_json::Result<Ledger> {
+        let mut ledger: Ledger = serde_json::from_reader(input)?;
+        // Snapshots taken before partial disputes don't record the disputed part of
+        // transactions, which was always their whole amount.
+        for transaction in ledger.transactions.values_mut() {
+            let state = transaction.state();
+            let disputed = [TransactionState::Disputed, TransactionState::Chargedback];
+            if disputed.contains(&state) && transaction.disputed().is_zero() {
+                *transaction = transaction.with_disputed(transaction.amount());
+            }
+        }
+        Ok(ledger)
+    }
+}
+
+/// Maps a failure of the transaction store while handling `transaction_id` to a transaction
+/// error.
+fn store_error(transaction_id: TransactionId) -> impl Fn(io::Error) -> TransactionError {
+    move |err| TransactionError::Store(transaction_id, err.kind())
+}
+
+/// Maps a failure of the transaction store while rolling back `transaction_id`, rejected with
+/// `error`, to a transaction error keeping `error` as its source.
+fn rollback_error(
+    transaction_id: TransactionId,
+    error: TransactionError,
+) -> impl Fn(io::Error) -> TransactionError {
+    move |err| TransactionError::RollbackFailed(transaction_id, err.kind(), Box::new(error.clone()))
+}
+
+impl<T: TransactionStore> Ledger<T> {
+    /// Creates an empty ledger keeping its transactions in `store`.
+    pub fn with_store(store: T) -> Ledger<T> {
+        Ledger::with_stores(store, AccountMap::new())
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore> Ledger<T, A> {
+    /// Creates an empty ledger keeping its transactions and accounts in the given stores.
+    pub fn with_stores(transactions: T, accounts: A) -> Ledger<T, A> {
         Ledger {
-            accounts: AccountMap::with_capacity(u16::MAX as usize),
-            transactions: TransactionMap::with_capacity(128),
+            accounts,
+            transactions,
+            sequence: 0,
+            window: VecDeque::new(),
+            evicted: IdRanges::default(),
+            dispute_policy: DisputePolicy::default(),
+            dispute_window: DisputeWindow::default(),
+            lock_policy: LockPolicy::default(),
+            precision: Precision::default(),
+            timestamp_tolerance: 0,
+            orphan_window: 0,
+            settled: Vec::new(),
+            orphans: VecDeque::new(),
+            histories: HashMap::new(),
+            shared_ids: None,
+            shard: None,
+            undo: None,
+            observer: None,
+            pending: Vec::new(),
         }
     }
 
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    /// Bounds the transactions kept for disputes. Transactions recorded while the window was
+    /// unbounded, such as those of a snapshot taken without a window, are never evicted.
+    pub fn with_dispute_window(mut self, dispute_window: DisputeWindow) -> Self {
+        self.dispute_window = dispute_window;
+        self
+    }
+
+    pub fn with_lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Accepts transactions up to `tolerance` older than the latest transaction of their client.
+    /// Older ones are rejected, as are any older ones with the default tolerance of zero.
+    /// Transactions without a timestamp are never checked.
+    pub fn with_timestamp_tolerance(mut self, tolerance: Timestamp) -> Self {
+        self.timestamp_tolerance = tolerance;
+        self
+    }
+
+    /// Keeps disputes, resolves and chargebacks of unknown transactions until `window` more
+    /// transactions are accepted, instead of rejecting them, and applies them if their
+    /// transaction arrives meanwhile. Such orphans are accepted when they are parked; their
+    /// outcome is reported later by [`Ledger::take_settled_orphans`].
+    pub fn with_orphan_window(mut self, window: u64) -> Self {
+        self.orphan_window = window;
+        self
+    }
+
+    /// Sends the events of this ledger to `observer`. Observers aren't part of snapshots.
+    ///
+    /// Events of a batch are only sent once the whole batch is applied. If it fails, only the
+    /// rejection of the failed transaction is sent.
+    pub fn with_observer(mut self, observer: Arc<dyn LedgerObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Whether orphan operations are waiting for `transaction_id`.
+    pub fn is_orphan(&self, transaction_id: TransactionId) -> bool {
+        self.orphans
+            .iter()
+            .any(|orphan| orphan.transaction_id == transaction_id)
+    }
+
+    /// Takes the outcomes of the orphan operations that were applied or expired since the
+    /// last call, in the order they were settled.
+    pub fn take_settled_orphans(&mut self) -> Vec<SettledOrphan> {
+        std::mem::take(&mut self.settled)
+    }
+
+    /// Expires all the orphan operations still waiting for their transaction, such as at the
+    /// end of the input.
+    pub fn expire_orphans(&mut self) {
+        self.log_orphans();
+        for orphan in std::mem::take(&mut self.orphans) {
+            self.settle_expired(orphan);
+        }
+    }
+
+    /// Dispute history of `transaction_id`, if it was ever disputed.
+    pub fn history(&self, transaction_id: TransactionId) -> Option<&DisputeHistory> {
+        self.histories.get(&transaction_id)
+    }
+
+    /// Records a dispute or resolve of `transaction_id` as `reference` in its history, creating
+    /// it on its first dispute.
+    fn record_event(
+        &mut self,
+        transaction_id: TransactionId,
+        operation: Operation,
+        reference: u64,
+    ) {
+        let previous = self.histories.get(&transaction_id).copied();
+        self.log(|| Undo::History(transaction_id, previous));
+        self.histories
+            .entry(transaction_id)
+            .or_default()
+            .push(operation, reference);
+    }
+
+    /// Number of transactions accepted by this ledger since it was created.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
     pub fn get_transaction_and_account_mut(
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
-    ) -> Result<(&mut Transaction, &mut Account), TransactionError> {
-        let maybe_disputed_transaction = self.transactions.get_mut(&transaction_id);
-        if maybe_disputed_transaction.is_none() {
-            return Err(TransactionError::UnknownTransactionId(transaction_id));
-        }
+    ) -> Result<(Transaction, &mut Account), TransactionError> {
+        let maybe_disputed_transaction = self
+            .transactions
+            .get(&transaction_id)
+            .map_err(store_error(transaction_id))?;
+        let Some(disputed_transaction) = maybe_disputed_transaction else {
+            if self.evicted.contains(&transaction_id) {
+                return Err(TransactionError::DisputeWindowExpired(transaction_id));
+            }
+            return Err(self.missing_transaction(transaction_id, client_id));
+        };
+        self.log_account(client_id);
         let maybe_account = self.accounts.get_mut(&client_id);
         if maybe_account.is_none() {
             return Err(TransactionError::UnknownClientId(client_id));
         }
-        Ok((maybe_disputed_transaction.unwrap(), maybe_account.unwrap()))
+        Ok((disputed_transaction, maybe_account.unwrap()))
+    }
+    /// Error for an operation of `client_id` naming a transaction this ledger doesn't hold.
+    ///
+    /// A shard of a split ledger rejects transactions claimed by other shards as the whole
+    /// ledger would: they belong to clients it doesn't handle, so the client ids can't match.
+    fn missing_transaction(
+        &self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> TransactionError {
+        let claimed = self.shared_ids.as_ref().and_then(|shared_ids| {
+            let shared_ids = shared_ids.lock().unwrap();
+            shared_ids.get(&transaction_id).copied()
+        });
+        let Some((owner, operation)) = claimed else {
+            return TransactionError::UnknownTransactionId(transaction_id);
+        };
+        if self.accounts.get(&client_id).is_none() {
+            TransactionError::UnknownClientId(client_id)
+        } else if !self.dispute_policy.allows(operation) {
+            TransactionError::NotDisputable(transaction_id)
+        } else {
+            TransactionError::MismatchedClientId(client_id, owner)
+        }
+    }
+    /// Account holding the funds of `transaction`, see [`Transaction::holder`].
+    fn holder_account_mut(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<&mut Account, TransactionError> {
+        let holder = transaction.holder();
+        self.log_account(holder);
+        self.accounts
+            .get_mut(&holder)
+            .ok_or(TransactionError::UnknownClientId(holder))
+    }
+    /// Moves the amount of `transaction` from its client to `receiver`. If either side fails,
+    /// both accounts are left untouched.
+    fn transfer(&mut self, receiver: ClientId, transaction: &Transaction) -> TransactionResult {
+        let sender = transaction.client_id();
+        let (currency, amount) = (transaction.currency(), transaction.amount());
+        if let Some(account) = self.accounts.get_mut(&receiver) {
+            account
+                .check_locked()
+                .map_err(|err| TransactionError::AccountError(receiver, err))?;
+        }
+        self.get_or_insert_account_mut(sender)
+            .withdraw(currency, amount)
+            .map_err(|err| TransactionError::AccountError(sender, err))?;
+        let credited = self
+            .get_or_insert_account_mut(receiver)
+            .deposit(currency, amount);
+        if let Err(err) = credited {
+            // Can't fail, the amount was just withdrawn.
+            let _ = self
+                .get_or_insert_account_mut(sender)
+                .deposit(currency, amount);
+            return Err(TransactionError::AccountError(receiver, err));
+        }
+        Ok(())
+    }
+    /// Iterates over all accounts in ascending `ClientId` order.
+    pub fn accounts(&self) -> impl Iterator<Item = (&ClientId, &Account)> {
+        self.accounts.iter()
     }
     pub fn get_or_insert_account_mut(&mut self, client_id: ClientId) -> &mut Account {
-        self.accounts.entry(client_id).or_default()
+        self.log_account(client_id);
+        self.accounts.get_or_insert(client_id)
+    }
+
+    /// Reopens a locked account. This is an administrative operation that ignores the lock
+    /// policy.
+    pub fn unlock_account(&mut self, client_id: ClientId) -> TransactionResult {
+        self.log_account(client_id);
+        let account = self
+            .accounts
+            .get_mut(&client_id)
+            .ok_or(TransactionError::UnknownClientId(client_id))?;
+        account.unlock();
+        Ok(())
     }
 
     fn id_exists(&self, transaction_id: TransactionId) -> TransactionResult {
-        if self.transactions.contains_key(&transaction_id) {
+        let stored = self
+            .transactions
+            .contains_key(&transaction_id)
+            .map_err(store_error(transaction_id))?;
+        if stored || self.evicted.contains(&transaction_id) {
             Err(TransactionError::RepeatedTransactionId(transaction_id))
         } else {
             Ok(())
         }
     }
+    /// Reserves `transaction_id` for a new transaction, making sure no other shard of a split
+    /// ledger uses it.
+    fn claim_id(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+    ) -> TransactionResult {
+        self.id_exists(transaction_id)?;
+        if let Some(shared_ids) = self.shared_ids.as_ref() {
+            match shared_ids.lock().unwrap().entry(transaction_id) {
+                hash_map::Entry::Occupied(_) => {
+                    return Err(TransactionError::RepeatedTransactionId(transaction_id))
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert((transaction.client_id(), transaction.operation()));
+                }
+            }
+            self.log(|| Undo::Claimed(transaction_id));
+        }
+        Ok(())
+    }
+    fn release_id(&mut self, transaction_id: TransactionId) {
+        if let Some(shared_ids) = self.shared_ids.as_ref() {
+            shared_ids.lock().unwrap().remove(&transaction_id);
+            // The id is no longer ours, so rolling back must not release it again.
+            if let Some(undo) = self.undo.as_mut() {
+                let claimed = undo.iter().rposition(
+                    |change| matches!(change, Undo::Claimed(id) if *id == transaction_id),
+                );
+                if let Some(position) = claimed {
+                    undo.remove(position);
+                }
+            }
+        }
+    }
+    fn log_orphans(&mut self) {
+        if let Some(undo) = self.undo.as_mut() {
+            undo.push(Undo::Orphans(self.orphans.clone()));
+        }
+    }
+    fn settle_expired(&mut self, orphan: Orphan) {
+        let Orphan {
+            transaction_id,
+            transaction,
+            ..
+        } = orphan;
+        let error = TransactionError::OrphanExpired(transaction_id);
+        self.notify(LedgerEvent::Rejected {
+            transaction_id,
+            transaction,
+            error: error.clone(),
+        });
+        self.settled.push((transaction_id, transaction, Err(error)));
+    }
+    /// Applies the orphan operations waiting for `arrived`, a transaction that was just
+    /// recorded, and expires those waiting past their deadline.
+    fn settle_orphans(&mut self, arrived: Option<TransactionId>) {
+        let adopted = |orphan: &Orphan| Some(orphan.transaction_id) == arrived;
+        let expired = |orphan: &Orphan| orphan.deadline < self.sequence;
+        if !self.orphans.iter().any(adopted) && !self.orphans.front().is_some_and(expired) {
+            return;
+        }
+        self.log_orphans();
+        let (adopted, waiting) = std::mem::take(&mut self.orphans)
+            .into_iter()
+            .partition(adopted);
+        self.orphans = waiting;
+        for orphan in adopted {
+            let result = self.observed(orphan.transaction_id, &orphan.transaction, |ledger| {
+                ledger
+                    .apply(orphan.transaction_id, &orphan.transaction)
+                    .map(|()| true)
+            });
+            self.settled
+                .push((orphan.transaction_id, orphan.transaction, result));
+        }
+        while let Some(orphan) = self.orphans.pop_front() {
+            if orphan.deadline >= self.sequence {
+                self.orphans.push_front(orphan);
+                break;
+            }
+            self.settle_expired(orphan);
+        }
+    }
+    fn log(&mut self, change: impl FnOnce() -> Undo) {
+        if let Some(undo) = self.undo.as_mut() {
+            undo.push(change());
+        }
+    }
+    fn log_account(&mut self, client_id: ClientId) {
+        if let Some(undo) = self.undo.as_mut() {
+            let account = self.accounts.get(&client_id).cloned();
+            undo.push(Undo::Account(client_id, account));
+        }
+    }
+    /// Records a new deposit or withdrawal, evicting the oldest settled transactions past the
+    /// dispute window.
+    ///
+    /// The transaction is stored before `apply` changes the balances, and removed again if that
+    /// fails, so that balances never reflect a transaction the store doesn't hold.
+    fn record(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: Transaction,
+        apply: impl FnOnce(&mut Self) -> TransactionResult,
+    ) -> TransactionResult {
+        self.claim_id(transaction_id, &transaction)?;
+        let result = match self.update(transaction_id, transaction) {
+            Ok(()) => apply(self).or_else(|err| {
+                self.transactions
+                    .remove(&transaction_id)
+                    .map_err(rollback_error(transaction_id, err.clone()))?;
+                Err(err)
+            }),
+            stored => stored,
+        };
+        if result.is_err() {
+            self.release_id(transaction_id);
+            return result;
+        }
+        self.evict(transaction_id);
+        Ok(())
+    }
+    /// Pushes `transaction_id` into the dispute window and evicts the oldest settled
+    /// transactions past it. Eviction only saves memory, so a store failure stops it, leaving
+    /// the transactions in the window until the next one is recorded.
+    fn evict(&mut self, transaction_id: TransactionId) {
+        let DisputeWindow::Transactions(size) = self.dispute_window else {
+            return;
+        };
+        self.window.push_back(transaction_id);
+        self.log(|| Undo::WindowPushedBack);
+        // Transactions under dispute can't be evicted yet. They are set aside, so they don't
+        // count against the window, and put back in place afterwards.
+        let mut disputed = Vec::new();
+        while self.window.len() > size {
+            let oldest = self.window.pop_front().unwrap();
+            self.log(|| Undo::WindowPoppedFront(oldest));
+            let stored = match self.transactions.get(&oldest) {
+                Ok(stored) => stored,
+                Err(_) => {
+                    disputed.push(oldest);
+                    break;
+                }
+            };
+            match stored {
+                Some(transaction) if !transaction.state().is_settled() => disputed.push(oldest),
+                stored => {
+                    if self.transactions.remove(&oldest).is_err() {
+                        disputed.push(oldest);
+                        break;
+                    }
+                    self.log(|| Undo::Transaction(oldest, stored));
+                    if let Some(history) = self.histories.remove(&oldest) {
+                        self.log(|| Undo::History(oldest, Some(history)));
+                    }
+                    if self.evicted.insert(oldest) {
+                        self.log(|| Undo::Evicted(oldest));
+                    }
+                }
+            }
+        }
+        for transaction_id in disputed.into_iter().rev() {
+            self.window.push_front(transaction_id);
+            self.log(|| Undo::WindowPushedFront);
+        }
+    }
+    fn update(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: Transaction,
+    ) -> TransactionResult {
+        if self.undo.is_some() {
+            let previous = self
+                .transactions
+                .get(&transaction_id)
+                .map_err(store_error(transaction_id))?;
+            self.log(|| Undo::Transaction(transaction_id, previous));
+        }
+        self.transactions
+            .insert(transaction_id, transaction)
+            .map_err(store_error(transaction_id))
+    }
+
+    /// Applies `batch` all-or-nothing: if any of its transactions fails, every change made by
+    /// the previous ones, to balances and transaction states alike, is rolled back and the
+    /// position of the failed transaction in the batch is returned with its error.
+    ///
+    /// The accounts touched by the batch are copied before each change, so batches are meant to
+    /// group a few related transactions rather than whole inputs.
+    pub fn apply_batch(
+        &mut self,
+        batch: &[(TransactionId, Transaction)],
+    ) -> Result<(), (usize, TransactionError)> {
+        let sequence = self.sequence;
+        let settled = self.settled.len();
+        self.undo = Some(Vec::new());
+        let failure =
+            batch
+                .iter()
+                .enumerate()
+                .find_map(|(index, (transaction_id, transaction))| {
+                    let res = self.apply_transaction(*transaction_id, transaction);
+                    res.err().map(|err| (index, *transaction_id, err))
+                });
+        let undo = self.undo.take().unwrap_or_default();
+        let events = std::mem::take(&mut self.pending);
+        let Some((index, transaction_id, err)) = failure else {
+            events.iter().for_each(|event| self.notify(event.clone()));
+            return Ok(());
+        };
+        self.sequence = sequence;
+        self.settled.truncate(settled);
+        self.notify(LedgerEvent::Rejected {
+            transaction_id,
+            transaction: batch[index].1,
+            error: err.clone(),
+        });
+        match self.rollback(undo) {
+            Ok(()) => Err((index, err)),
+            Err(rollback) => Err((index, rollback_error(transaction_id, err)(rollback))),
+        }
+    }
+
+    fn rollback(&mut self, undo: Vec<Undo>) -> io::Result<()> {
+        for change in undo.into_iter().rev() {
+            match change {
+                Undo::Account(client_id, Some(account)) => {
+                    *self.accounts.get_or_insert(client_id) = account;
+                }
+                Undo::Account(client_id, None) => self.accounts.remove(&client_id),
+                Undo::Transaction(transaction_id, Some(transaction)) => {
+                    self.transactions.insert(transaction_id, transaction)?
+                }
+                Undo::Transaction(transaction_id, None) => {
+                    self.transactions.remove(&transaction_id)?
+                }
+                Undo::Evicted(transaction_id) => {
+                    self.evicted.remove(&transaction_id);
+                }
+                Undo::WindowPushedBack => {
+                    self.window.pop_back();
+                }
+                Undo::WindowPoppedFront(transaction_id) => self.window.push_front(transaction_id),
+                Undo::WindowPushedFront => {
+                    self.window.pop_front();
+                }
+                Undo::Claimed(transaction_id) => self.release_id(transaction_id),
+                Undo::Orphans(orphans) => self.orphans = orphans,
+                Undo::History(transaction_id, Some(history)) => {
+                    self.histories.insert(transaction_id, history);
+                }
+                Undo::History(transaction_id, None) => {
+                    self.histories.remove(&transaction_id);
+                }
+            }
+        }
+        Ok(())
+    }
     pub fn apply_transaction(
         &mut self,
         transaction_id: TransactionId,
         transaction: &Transaction,
+    ) -> TransactionResult {
+        self.observed(transaction_id, transaction, |ledger| {
+            ledger.accept(transaction_id, transaction)
+        })?;
+        let operation = transaction.operation();
+        self.settle_orphans(operation.has_amount().then_some(transaction_id));
+        Ok(())
+    }
+
+    /// Applies `transaction`, or parks it as an orphan. Returns whether it was applied.
+    fn accept(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+    ) -> Result<bool, TransactionError> {
+        let client_id = transaction.client_id();
+        let timestamp = transaction.timestamp();
+        if let (Some(timestamp), Some(latest)) = (
+            timestamp,
+            self.accounts.get(&client_id).and_then(Account::timestamp),
+        ) {
+            if timestamp.saturating_add(self.timestamp_tolerance) < latest {
+                return Err(TransactionError::OutOfOrder(transaction_id, timestamp));
+            }
+        }
+        let applied = match self.apply(transaction_id, transaction) {
+            Err(TransactionError::UnknownTransactionId(_))
+                if self.orphan_window > 0
+                    && matches!(
+                        transaction.operation(),
+                        Operation::Dispute
+                            | Operation::Resolve
+                            | Operation::Chargeback
+                            | Operation::Representment
+                            | Operation::PreArbitration
+                    ) =>
+            {
+                self.log_orphans();
+                self.orphans.push_back(Orphan {
+                    transaction_id,
+                    transaction: *transaction,
+                    deadline: self.sequence + 1 + self.orphan_window,
+                });
+                false
+            }
+            result => result.map(|()| true)?,
+        };
+        if let Some(timestamp) = timestamp {
+            self.log_account(client_id);
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                account.record_timestamp(timestamp);
+            }
+        }
+        self.sequence += 1;
+        Ok(applied)
+    }
+
+    /// Sends `event` to the observer, holding it back while a batch is applied.
+    fn notify(&mut self, event: LedgerEvent) {
+        let Some(observer) = self.observer.as_ref() else {
+            return;
+        };
+        if self.undo.is_some() {
+            self.pending.push(event);
+        } else {
+            observer.on_event(&event);
+        }
+    }
+    /// Accounts and currency whose balances `transaction` may change.
+    fn touched(
+        &self,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+    ) -> Vec<(ClientId, Currency)> {
+        let client_id = transaction.client_id();
+        let referenced = match transaction.operation().has_amount() {
+            true => None,
+            false => self.transactions.get(&transaction_id).ok().flatten(),
+        };
+        let (currency, other) = match referenced {
+            Some(referenced) => (referenced.currency(), Some(referenced.holder())),
+            None => (transaction.currency(), transaction.counterparty()),
+        };
+        let other = other.filter(|other| *other != client_id);
+        [Some(client_id), other]
+            .into_iter()
+            .flatten()
+            .map(|client_id| (client_id, currency))
+            .collect()
+    }
+    /// Runs `apply` for `transaction` and notifies the observer of its outcome.
+    fn observed(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+        apply: impl FnOnce(&mut Self) -> Result<bool, TransactionError>,
+    ) -> TransactionResult {
+        if self.observer.is_none() {
+            return apply(self).map(|_| ());
+        }
+        let touched = self.touched(transaction_id, transaction);
+        let before: Vec<_> = touched
+            .iter()
+            .map(|(client_id, currency)| {
+                let account = self.accounts.get(client_id);
+                account.map(|account| (account.balance(*currency), account.locked()))
+            })
+            .collect();
+        let transaction = *transaction;
+        let applied = match apply(self) {
+            Err(error) => {
+                self.notify(LedgerEvent::Rejected {
+                    transaction_id,
+                    transaction,
+                    error: error.clone(),
+                });
+                return Err(error);
+            }
+            Ok(applied) => applied,
+        };
+        if !applied {
+            self.notify(LedgerEvent::Parked {
+                transaction_id,
+                transaction,
+            });
+            return Ok(());
+        }
+        let mut changes = Vec::new();
+        let mut locks = Vec::new();
+        for ((client_id, currency), before) in touched.into_iter().zip(before) {
+            let Some(account) = self.accounts.get(&client_id) else {
+                continue;
+            };
+            let (balance, locked) = before.unwrap_or_default();
+            changes.push(BalanceChange {
+                client_id,
+                currency,
+                before: balance,
+                after: account.balance(currency),
+            });
+            match (locked, account.locked()) {
+                (false, true) => locks.push(LedgerEvent::AccountLocked(client_id)),
+                (true, false) => locks.push(LedgerEvent::AccountUnlocked(client_id)),
+                _ => {}
+            }
+        }
+        let event = LedgerEvent::applied(Applied {
+            transaction_id,
+            transaction,
+            changes,
+        });
+        for event in event.into_iter().chain(locks) {
+            self.notify(event);
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
     ) -> TransactionResult {
         if transaction.amount() < Number::ZERO {
             return Err(TransactionError::InvalidAmount(
@@ -65,62 +927,164 @@ impl Ledger {
                 transaction.amount(),
             ));
         }
-        match transaction.operation() {
-            Operation::Deposit => {
-                self.id_exists(transaction_id)?;
-                let account = self.get_or_insert_account_mut(transaction.client_id());
-                account
-                    .deposit(transaction.amount())
-                    .map_err(|err| TransactionError::AccountError(transaction.client_id(), err))?;
-                self.transactions.insert(transaction_id, *transaction);
+        let amount = self
+            .precision
+            .normalize(transaction_id, transaction.amount())?;
+        if transaction.operation().has_amount() && amount.is_zero() {
+            return Err(TransactionError::InvalidAmount(transaction_id, amount));
+        }
+        let transaction = &transaction.with_amount(amount);
+        let policy = self.dispute_policy;
+        let reference = transaction.reference().unwrap_or(self.sequence);
+        let lock_policy = self.lock_policy;
+        let operation = transaction.operation();
+        let account_error = |err| TransactionError::AccountError(transaction.client_id(), err);
+        match operation {
+            Operation::Deposit => self.record(transaction_id, *transaction, |ledger| {
+                let account = ledger.get_or_insert_account_mut(transaction.client_id());
+                lock_policy
+                    .check(operation, account)
+                    .and_then(|()| account.deposit(transaction.currency(), transaction.amount()))
+                    .map_err(account_error)
+            }),
+            Operation::Withdrawal => self.record(transaction_id, *transaction, |ledger| {
+                ledger
+                    .get_or_insert_account_mut(transaction.client_id())
+                    .withdraw(transaction.currency(), transaction.amount())
+                    .map_err(account_error)
+            }),
+            Operation::Dispute => {
+                let (mut disputed_transaction, _) =
+                    self.get_transaction_and_account_mut(transaction_id, transaction.client_id())?;
+                transaction.check_valid_dispute(transaction_id, &disputed_transaction, &policy)?;
+                disputed_transaction.check_transition(transaction_id, operation)?;
+                let disputes = self
+                    .histories
+                    .get(&transaction_id)
+                    .map_or(0, |history| history.disputes);
+                policy.check_dispute(transaction_id, &disputed_transaction, disputes)?;
+                let amount =
+                    disputed_transaction.dispute_amount(transaction_id, transaction.amount())?;
+                let holder = disputed_transaction.holder();
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                lock_policy
+                    .check(operation, account)
+                    .map_err(|err| TransactionError::AccountError(holder, err))?;
+                disputed_transaction.dispute(account, amount)?;
+                self.update(transaction_id, disputed_transaction)?;
+                self.record_event(transaction_id, operation, reference);
                 Ok(())
             }
-            Operation::Withdrawal => {
-                self.id_exists(transaction_id)?;
-                let account = self.get_or_insert_account_mut(transaction.client_id());
-                account
-                    .withdraw(transaction.amount())
-                    .map_err(|err| TransactionError::AccountError(transaction.client_id(), err))?;
-                self.transactions.insert(transaction_id, *transaction);
+            Operation::Resolve => {
+                let (mut disputed_transaction, _) =
+                    self.get_transaction_and_account_mut(transaction_id, transaction.client_id())?;
+                transaction.check_valid_dispute(transaction_id, &disputed_transaction, &policy)?;
+                disputed_transaction.check_transition(transaction_id, operation)?;
+                let holder = disputed_transaction.holder();
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                lock_policy
+                    .check(operation, account)
+                    .map_err(|err| TransactionError::AccountError(holder, err))?;
+                disputed_transaction.resolve(account)?;
+                self.update(transaction_id, disputed_transaction)?;
+                self.record_event(transaction_id, operation, reference);
                 Ok(())
             }
-            Operation::Dispute => {
-                let (disputed_transaction, account) =
+            Operation::Chargeback => {
+                let (mut disputed_transaction, _) =
                     self.get_transaction_and_account_mut(transaction_id, transaction.client_id())?;
-                transaction.check_valid_dispute(transaction_id, disputed_transaction)?;
-                disputed_transaction.state_matches_or(
-                    TransactionState::Ok,
-                    TransactionError::AlreadyDisputed(transaction_id),
-                )?;
-                disputed_transaction.dispute(account)
+                transaction.check_valid_dispute(transaction_id, &disputed_transaction, &policy)?;
+                disputed_transaction.check_transition(transaction_id, operation)?;
+                let holder = disputed_transaction.holder();
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                lock_policy
+                    .check(operation, account)
+                    .map_err(|err| TransactionError::AccountError(holder, err))?;
+                if disputed_transaction.operation() == Operation::Transfer {
+                    // The funds go back to the sender first, as taking them from the receiver
+                    // can't fail.
+                    self.get_or_insert_account_mut(transaction.client_id())
+                        .deposit(
+                            disputed_transaction.currency(),
+                            disputed_transaction.disputed(),
+                        )
+                        .map_err(account_error)?;
+                }
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                disputed_transaction.chargeback(account)?;
+                self.update(transaction_id, disputed_transaction)
             }
-            Operation::Resolve => {
-                let (disputed_transaction, account) =
+            Operation::Representment => {
+                let (mut disputed_transaction, _) =
                     self.get_transaction_and_account_mut(transaction_id, transaction.client_id())?;
-                transaction.check_valid_dispute(transaction_id, disputed_transaction)?;
-                disputed_transaction.state_matches_or(
-                    TransactionState::Disputed,
-                    TransactionError::UndisputedTransaction(transaction_id),
-                )?;
-                disputed_transaction.resolve(account)
+                transaction.check_valid_dispute(transaction_id, &disputed_transaction, &policy)?;
+                disputed_transaction.check_transition(transaction_id, operation)?;
+                let holder = disputed_transaction.holder();
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                lock_policy
+                    .check(operation, account)
+                    .map_err(|err| TransactionError::AccountError(holder, err))?;
+                let (currency, amount) = (
+                    disputed_transaction.currency(),
+                    disputed_transaction.disputed(),
+                );
+                if disputed_transaction.operation() == Operation::Transfer {
+                    // The funds the chargeback gave back to the sender go to the receiver again.
+                    self.get_or_insert_account_mut(transaction.client_id())
+                        .reclaim(currency, amount)
+                        .map_err(account_error)?;
+                }
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                let represented = disputed_transaction.represent(account);
+                if represented.is_err() && disputed_transaction.operation() == Operation::Transfer {
+                    // Can't fail, the amount was just withdrawn.
+                    let _ = self
+                        .get_or_insert_account_mut(transaction.client_id())
+                        .deposit(currency, amount);
+                }
+                represented?;
+                self.update(transaction_id, disputed_transaction)
             }
-            Operation::Chargeback => {
-                let (disputed_transaction, account) =
+            Operation::PreArbitration => {
+                let (mut disputed_transaction, _) =
                     self.get_transaction_and_account_mut(transaction_id, transaction.client_id())?;
-                transaction.check_valid_dispute(transaction_id, disputed_transaction)?;
-                disputed_transaction.state_matches_or(
-                    TransactionState::Disputed,
-                    TransactionError::UndisputedTransaction(transaction_id),
-                )?;
-                disputed_transaction.chargeback(account)
+                transaction.check_valid_dispute(transaction_id, &disputed_transaction, &policy)?;
+                disputed_transaction.check_transition(transaction_id, operation)?;
+                let holder = disputed_transaction.holder();
+                let account = self.holder_account_mut(&disputed_transaction)?;
+                lock_policy
+                    .check(operation, account)
+                    .map_err(|err| TransactionError::AccountError(holder, err))?;
+                disputed_transaction.pre_arbitrate(account)?;
+                self.update(transaction_id, disputed_transaction)
+            }
+            Operation::Unlock => {
+                if !lock_policy.unlocks {
+                    return Err(TransactionError::UnlockNotAllowed(transaction.client_id()));
+                }
+                self.unlock_account(transaction.client_id())
+            }
+            Operation::Transfer => {
+                let receiver = transaction
+                    .counterparty()
+                    .filter(|receiver| *receiver != transaction.client_id())
+                    .ok_or(TransactionError::InvalidTransfer(transaction_id))?;
+                if let Some((shard, shards)) = self.shard {
+                    if <Ledger>::shard_of(receiver, shards) != shard {
+                        return Err(TransactionError::CrossShardTransfer(transaction_id));
+                    }
+                }
+                self.record(transaction_id, *transaction, |ledger| {
+                    ledger.transfer(receiver, transaction)
+                })
             }
         }
     }
 }
 
-impl IntoIterator for Ledger {
-    type Item = <AccountMap as IntoIterator>::Item;
-    type IntoIter = <AccountMap as IntoIterator>::IntoIter;
+impl<T, A: IntoIterator<Item = (ClientId, Account)>> IntoIterator for Ledger<T, A> {
+    type Item = (ClientId, Account);
+    type IntoIter = A::IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
         self.accounts.into_iter()
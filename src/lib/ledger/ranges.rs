@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+
+use crate::transactions::TransactionId;
+
+/// Set of transaction ids kept as ranges of consecutive ids, so that it stays small when ids are
+/// mostly increasing, as those evicted from a dispute window are.
+///
+/// Serialized as a list of ids and `[first, last]` ranges, so that snapshots holding plain lists
+/// of ids can still be loaded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IdRanges {
+    /// Last id of each range, by first id.
+    ranges: BTreeMap<u32, u32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Id(u32),
+    Range(u32, u32),
+}
+
+impl IdRanges {
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Number of ranges, which is what the set costs in memory.
+    #[cfg(test)]
+    pub fn ranges(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Range holding `id`, if any.
+    fn range(&self, id: u32) -> Option<(u32, u32)> {
+        let (first, last) = self.ranges.range(..=id).next_back()?;
+        (id <= *last).then_some((*first, *last))
+    }
+
+    pub fn contains(&self, transaction_id: &TransactionId) -> bool {
+        self.range(transaction_id.0).is_some()
+    }
+
+    /// Adds `transaction_id`, joining it to the ranges next to it. Returns whether it was new.
+    pub fn insert(&mut self, transaction_id: TransactionId) -> bool {
+        let id = transaction_id.0;
+        if self.range(id).is_some() {
+            return false;
+        }
+        self.insert_range(id, id);
+        true
+    }
+
+    /// Adds the ids from `first` to `last`, joining the ranges they overlap or touch.
+    fn insert_range(&mut self, mut first: u32, mut last: u32) {
+        let before = self.ranges.range(..first).next_back();
+        if let Some((&start, &end)) = before.filter(|(_, end)| end.saturating_add(1) >= first) {
+            self.ranges.remove(&start);
+            first = start;
+            last = last.max(end);
+        }
+        let joined: Vec<_> = self
+            .ranges
+            .range(first..=last.saturating_add(1))
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (start, end) in joined {
+            self.ranges.remove(&start);
+            last = last.max(end);
+        }
+        self.ranges.insert(first, last);
+    }
+
+    /// Removes `transaction_id`, splitting the range holding it.
+    pub fn remove(&mut self, transaction_id: &TransactionId) {
+        let id = transaction_id.0;
+        let Some((first, last)) = self.range(id) else {
+            return;
+        };
+        self.ranges.remove(&first);
+        if first < id {
+            self.ranges.insert(first, id - 1);
+        }
+        if id < last {
+            self.ranges.insert(id + 1, last);
+        }
+    }
+
+    /// Adds all the ids of `other`.
+    pub fn extend(&mut self, other: IdRanges) {
+        for (first, last) in other.ranges {
+            self.insert_range(first, last);
+        }
+    }
+}
+
+impl serde::Serialize for IdRanges {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges.iter().map(|(first, last)| match first == last {
+            true => Entry::Id(*first),
+            false => Entry::Range(*first, *last),
+        }))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IdRanges {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut ids = IdRanges::default();
+        for entry in Vec::<Entry>::deserialize(deserializer)? {
+            match entry {
+                Entry::Id(id) => ids.insert_range(id, id),
+                Entry::Range(first, last) => ids.insert_range(first, last.max(first)),
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod ranges_tests {
+    use super::*;
+
+    #[test]
+    fn join_and_split_ranges() {
+        let mut ids = IdRanges::default();
+        for id in [1, 3, 2, 5] {
+            assert!(ids.insert(TransactionId(id)));
+        }
+        assert!(!ids.insert(TransactionId(2)));
+        assert_eq!(ids.ranges(), 2);
+        assert!(ids.contains(&TransactionId(3)));
+        assert!(!ids.contains(&TransactionId(4)));
+        ids.remove(&TransactionId(2));
+        assert_eq!(ids.ranges(), 3);
+        assert!(!ids.contains(&TransactionId(2)));
+        assert!(ids.contains(&TransactionId(1)));
+    }
+
+    #[test]
+    fn load_plain_list_of_ids() {
+        let ids: IdRanges = serde_json::from_str("[4, 1, 2, 3, 7]").unwrap();
+        assert_eq!(ids.ranges(), 2);
+        assert_eq!(serde_json::to_string(&ids).unwrap(), "[[1,4],7]");
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Seek, Write};
+use std::{fs, iter, path::Path, str, vec};
+
+use crate::account::{Account, ClientId, Currency, Number};
+use crate::transactions::{Operation, Transaction, TransactionId, TransactionState};
+
+/// Storage for the accounts of a ledger.
+///
+/// There are at most as many accounts as client ids, so stores are expected to hold them in
+/// memory and hand out references to them.
+pub trait AccountStore {
+    fn get(&self, client_id: &ClientId) -> Option<&Account>;
+    fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Account>;
+    /// Account of `client_id`, opening an empty one if the client has none yet.
+    fn get_or_insert(&mut self, client_id: ClientId) -> &mut Account;
+    fn remove(&mut self, client_id: &ClientId);
+    /// Iterates over all accounts in ascending `ClientId` order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (&ClientId, &Account)> + '_>;
+}
+
+impl AccountStore for BTreeMap<ClientId, Account> {
+    fn get(&self, client_id: &ClientId) -> Option<&Account> {
+        BTreeMap::get(self, client_id)
+    }
+    fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Account> {
+        BTreeMap::get_mut(self, client_id)
+    }
+    fn get_or_insert(&mut self, client_id: ClientId) -> &mut Account {
+        self.entry(client_id).or_default()
+    }
+    fn remove(&mut self, client_id: &ClientId) {
+        BTreeMap::remove(self, client_id);
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (&ClientId, &Account)> + '_> {
+        Box::new(BTreeMap::iter(self))
+    }
+}
+
+/// Accounts stored in a vector indexed by client id, trading up to a few megabytes of memory
+/// for lookups without any search.
+#[derive(Default)]
+pub struct DenseAccountStore {
+    accounts: Vec<Option<(ClientId, Account)>>,
+}
+
+impl DenseAccountStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountStore for DenseAccountStore {
+    fn get(&self, client_id: &ClientId) -> Option<&Account> {
+        let (_, account) = self.accounts.get(client_id.0 as usize)?.as_ref()?;
+        Some(account)
+    }
+    fn get_mut(&mut self, client_id: &ClientId) -> Option<&mut Account> {
+        let (_, account) = self.accounts.get_mut(client_id.0 as usize)?.as_mut()?;
+        Some(account)
+    }
+    fn get_or_insert(&mut self, client_id: ClientId) -> &mut Account {
+        let index = client_id.0 as usize;
+        if self.accounts.len() <= index {
+            self.accounts.resize_with(index + 1, || None);
+        }
+        let (_, account) =
+            self.accounts[index].get_or_insert_with(|| (client_id, Account::default()));
+        account
+    }
+    fn remove(&mut self, client_id: &ClientId) {
+        if let Some(account) = self.accounts.get_mut(client_id.0 as usize) {
+            *account = None;
+        }
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (&ClientId, &Account)> + '_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .flatten()
+                .map(|(client_id, account)| (client_id, account)),
+        )
+    }
+}
+
+impl IntoIterator for DenseAccountStore {
+    type Item = (ClientId, Account);
+    type IntoIter = iter::Flatten<vec::IntoIter<Option<(ClientId, Account)>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.accounts.into_iter().flatten()
+    }
+}
+
+/// Storage for the deposits and withdrawals of a ledger, looked up again by disputes, resolves
+/// and chargebacks.
+pub trait TransactionStore {
+    fn get(&self, transaction_id: &TransactionId) -> io::Result<Option<Transaction>>;
+    fn contains_key(&self, transaction_id: &TransactionId) -> io::Result<bool> {
+        Ok(self.get(transaction_id)?.is_some())
+    }
+    /// Stores `transaction`, replacing any transaction with the same id.
+    fn insert(&mut self, transaction_id: TransactionId, transaction: Transaction)
+        -> io::Result<()>;
+    fn remove(&mut self, transaction_id: &TransactionId) -> io::Result<()>;
+}
+
+impl TransactionStore for HashMap<TransactionId, Transaction> {
+    fn get(&self, transaction_id: &TransactionId) -> io::Result<Option<Transaction>> {
+        Ok(HashMap::get(self, transaction_id).copied())
+    }
+    fn contains_key(&self, transaction_id: &TransactionId) -> io::Result<bool> {
+        Ok(HashMap::contains_key(self, transaction_id))
+    }
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: Transaction,
+    ) -> io::Result<()> {
+        HashMap::insert(self, transaction_id, transaction);
+        Ok(())
+    }
+    fn remove(&mut self, transaction_id: &TransactionId) -> io::Result<()> {
+        HashMap::remove(self, transaction_id);
+        Ok(())
+    }
+}
+
+/// Size of a transaction record: a presence flag, the client id, the amount, the operation, the
+/// state, the currency and the counterparty, if any, as a flag and a client id, followed by the
+/// disputed amount, the timestamp and the reference.
+const RECORD_SIZE: usize = 1 + 2 + 16 + 1 + 1 + 3 + 1 + 2 + 16 + 1 + 8 + 9;
+
+/// Transactions stored as fixed-size records in a file, each at the offset given by its id, so
+/// that a lookup is a single read however long the history is.
+///
+/// Offsets of ids never used are left as holes, which take no space on file systems supporting
+/// sparse files.
+pub struct FileTransactionStore {
+    file: fs::File,
+}
+
+impl FileTransactionStore {
+    /// Stores transactions in `file`, keeping the records it already holds. The file must be
+    /// open for reading and writing.
+    pub fn new(file: fs::File) -> Self {
+        Self { file }
+    }
+
+    /// Opens the store at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self::new(file))
+    }
+
+    fn seek(&self, transaction_id: &TransactionId) -> io::Result<()> {
+        let offset = transaction_id.0 as u64 * RECORD_SIZE as u64;
+        (&self.file).seek(io::SeekFrom::Start(offset))?;
+        Ok(())
+    }
+}
+
+impl TransactionStore for FileTransactionStore {
+    fn get(&self, transaction_id: &TransactionId) -> io::Result<Option<Transaction>> {
+        self.seek(transaction_id)?;
+        let mut record = [0; RECORD_SIZE];
+        match (&self.file).read_exact(&mut record) {
+            Ok(()) => decode(&record),
+            // Past the end of the file, no record was ever written.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: Transaction,
+    ) -> io::Result<()> {
+        self.seek(&transaction_id)?;
+        self.file.write_all(&encode(&transaction))
+    }
+    fn remove(&mut self, transaction_id: &TransactionId) -> io::Result<()> {
+        if self.contains_key(transaction_id)? {
+            self.seek(transaction_id)?;
+            self.file.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+fn encode(transaction: &Transaction) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[0] = 1;
+    record[1..3].copy_from_slice(&transaction.client_id().0.to_le_bytes());
+    record[3..19].copy_from_slice(&transaction.amount().serialize());
+    record[19] = match transaction.operation() {
+        Operation::Deposit => 0,
+        Operation::Withdrawal => 1,
+        Operation::Dispute => 2,
+        Operation::Chargeback => 3,
+        Operation::Resolve => 4,
+        Operation::Unlock => 5,
+        Operation::Transfer => 6,
+        Operation::Representment => 7,
+        Operation::PreArbitration => 8,
+    };
+    record[20] = match transaction.state() {
+        TransactionState::Ok => 0,
+        TransactionState::Disputed => 1,
+        TransactionState::Chargedback => 2,
+        TransactionState::Represented => 3,
+        TransactionState::PreArbitration => 4,
+        TransactionState::Upheld => 5,
+        TransactionState::Reversed => 6,
+        TransactionState::Resolved => 7,
+    };
+    record[21..24].copy_from_slice(transaction.currency().as_str().as_bytes());
+    if let Some(counterparty) = transaction.counterparty() {
+        record[24] = 1;
+        record[25..27].copy_from_slice(&counterparty.0.to_le_bytes());
+    }
+    record[27..43].copy_from_slice(&transaction.disputed().serialize());
+    if let Some(timestamp) = transaction.timestamp() {
+        record[43] = 1;
+        record[44..52].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    encode_sequence(&mut record[RECORD_SIZE - 9..], transaction.reference());
+    record
+}
+
+/// Decodes a record written by [`encode`]. Corrupt records are reported as
+/// [`io::ErrorKind::InvalidData`].
+fn decode(record: &[u8; RECORD_SIZE]) -> io::Result<Option<Transaction>> {
+    if !decode_flag(record[0])? {
+        return Ok(None);
+    }
+    let client_id = ClientId(u16::from_le_bytes([record[1], record[2]]));
+    let amount = decode_number(&record[3..19]).ok_or_else(|| invalid("amount"))?;
+    let operation = match record[19] {
+        0 => Operation::Deposit,
+        1 => Operation::Withdrawal,
+        2 => Operation::Dispute,
+        3 => Operation::Chargeback,
+        4 => Operation::Resolve,
+        5 => Operation::Unlock,
+        6 => Operation::Transfer,
+        7 => Operation::Representment,
+        8 => Operation::PreArbitration,
+        _ => return Err(invalid("operation")),
+    };
+    let state = match record[20] {
+        0 => TransactionState::Ok,
+        1 => TransactionState::Disputed,
+        2 => TransactionState::Chargedback,
+        3 => TransactionState::Represented,
+        4 => TransactionState::PreArbitration,
+        5 => TransactionState::Upheld,
+        6 => TransactionState::Reversed,
+        7 => TransactionState::Resolved,
+        _ => return Err(invalid("transaction state")),
+    };
+    let currency: Currency = str::from_utf8(&record[21..24])
+        .ok()
+        .and_then(|currency| currency.parse().ok())
+        .ok_or_else(|| invalid("currency"))?;
+    let mut transaction = Transaction::new(client_id, amount, operation)
+        .with_currency(currency)
+        .with_state(state)
+        .with_disputed(decode_number(&record[27..43]).ok_or_else(|| invalid("disputed amount"))?);
+    if decode_flag(record[24])? {
+        let counterparty = ClientId(u16::from_le_bytes([record[25], record[26]]));
+        transaction = transaction.with_counterparty(counterparty);
+    }
+    if decode_flag(record[43])? {
+        let timestamp = u64::from_le_bytes(record[44..52].try_into().unwrap());
+        transaction = transaction.with_timestamp(timestamp);
+    }
+    if let Some(reference) = decode_sequence(&record[RECORD_SIZE - 9..])? {
+        transaction = transaction.with_reference(reference);
+    }
+    Ok(Some(transaction))
+}
+
+/// Encodes an optional sequence number as a presence flag followed by the number.
+fn encode_sequence(field: &mut [u8], sequence: Option<u64>) {
+    if let Some(sequence) = sequence {
+        field[0] = 1;
+        field[1..9].copy_from_slice(&sequence.to_le_bytes());
+    }
+}
+
+fn decode_sequence(field: &[u8]) -> io::Result<Option<u64>> {
+    let present = decode_flag(field[0])?;
+    Ok(present.then(|| u64::from_le_bytes(field[1..9].try_into().unwrap())))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {what}"))
+}
+
+fn decode_flag(flag: u8) -> io::Result<bool> {
+    match flag {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid("presence flag")),
+    }
+}
+
+/// Decodes a number written by `Number::serialize`, or `None` if its flags, which only hold a
+/// scale of at most 28 and a sign, are corrupt.
+fn decode_number(bytes: &[u8]) -> Option<Number> {
+    let bytes: [u8; 16] = bytes.try_into().unwrap();
+    let flags = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let scale = (flags >> 16) & 0xFF;
+    (flags & !0x80FF_0000 == 0 && scale <= 28).then(|| Number::deserialize(bytes))
+}
+
+#[cfg(test)]
+mod store_tests {
+    use super::RECORD_SIZE;
+    use super::{decode, encode, DenseAccountStore, FileTransactionStore, TransactionStore};
+    use crate::account::{num, ClientId, Currency, Number};
+    use crate::ledger::Ledger;
+    use crate::transactions::{
+        Operation, Transaction, TransactionError, TransactionId, TransactionState,
+    };
+    use std::collections::HashMap;
+    use std::io;
+
+    /// Transaction store whose writes fail once it holds `capacity` transactions.
+    struct FullStore {
+        transactions: HashMap<TransactionId, Transaction>,
+        capacity: usize,
+        /// Whether removals succeed.
+        removals: bool,
+    }
+
+    impl TransactionStore for FullStore {
+        fn get(&self, transaction_id: &TransactionId) -> io::Result<Option<Transaction>> {
+            Ok(self.transactions.get(transaction_id).copied())
+        }
+        fn insert(
+            &mut self,
+            transaction_id: TransactionId,
+            transaction: Transaction,
+        ) -> io::Result<()> {
+            if self.transactions.len() >= self.capacity {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            self.transactions.insert(transaction_id, transaction);
+            Ok(())
+        }
+        fn remove(&mut self, transaction_id: &TransactionId) -> io::Result<()> {
+            if !self.removals {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            self.transactions.remove(transaction_id);
+            Ok(())
+        }
+    }
+
+    /// Opens an empty store in a file named after `test`, and the process so that concurrent
+    /// test runs don't share it.
+    fn temporary_store(test: &str) -> FileTransactionStore {
+        let name = format!("crab-{test}-{}.bin", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        FileTransactionStore::open(&path).unwrap()
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let mut store = temporary_store("file_store_round_trip");
+        let eur: Currency = "EUR".parse().unwrap();
+        let transaction = Transaction::new(ClientId(7), num!(12.3456), Operation::Transfer)
+            .with_currency(eur)
+            .with_counterparty(ClientId(9))
+            .with_disputed(num!(2.5))
+            .with_timestamp(1_700_000_000)
+            .with_reference(12);
+        assert_eq!(store.get(&TransactionId(3)).unwrap(), None);
+        store.insert(TransactionId(3), transaction).unwrap();
+        assert_eq!(store.get(&TransactionId(3)).unwrap(), Some(transaction));
+        assert!(!store.contains_key(&TransactionId(2)).unwrap());
+        assert!(!store.contains_key(&TransactionId(4)).unwrap());
+        store.remove(&TransactionId(3)).unwrap();
+        assert_eq!(store.get(&TransactionId(3)).unwrap(), None);
+    }
+
+    #[test]
+    fn corrupt_records_are_invalid_data() {
+        let transaction = Transaction::new(ClientId(1), num!(1.5), Operation::Deposit)
+            .with_timestamp(5)
+            .with_reference(2);
+        let record = encode(&transaction);
+        assert_eq!(decode(&record).unwrap(), Some(transaction));
+        // Presence flags, flags and scales of the amounts, operation and state.
+        let corruptions = [(0, 2), (3, 1), (5, 29), (6, 1), (19, 9), (20, 8), (24, 2)];
+        let corruptions =
+            corruptions
+                .into_iter()
+                .chain([(29, 0xFF), (43, 3), (RECORD_SIZE - 9, 4)]);
+        for (offset, byte) in corruptions {
+            let mut corrupt = record;
+            corrupt[offset] = byte;
+            let err = decode(&corrupt).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "byte {offset}");
+        }
+    }
+
+    #[test]
+    fn ledger_with_file_store() {
+        let store = temporary_store("ledger_with_file_store");
+        let mut ledger = Ledger::with_store(store);
+        let transactions = [
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+            ),
+            (
+                TransactionId(2),
+                Transaction::new(ClientId(1), num!(20.0), Operation::Deposit),
+            ),
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+            ),
+            (
+                TransactionId(2),
+                Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+            ),
+            (
+                TransactionId(1),
+                Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+            ),
+            (
+                TransactionId(2),
+                Transaction::new(ClientId(1), Number::ZERO, Operation::Chargeback),
+            ),
+        ];
+        for (transaction_id, transaction) in &transactions {
+            ledger
+                .apply_transaction(*transaction_id, transaction)
+                .unwrap();
+        }
+        let res = ledger.apply_transaction(
+            TransactionId(2),
+            &Transaction::new(ClientId(1), num!(1.0), Operation::Deposit),
+        );
+        assert!(res.is_err());
+        let (_, account) = ledger.accounts().next().unwrap();
+        assert_eq!(account.available(), num!(50.0));
+        assert_eq!(account.held(), Number::ZERO);
+        assert!(account.locked());
+        let stored = ledger.transactions.get(&TransactionId(2)).unwrap().unwrap();
+        assert_eq!(stored.state(), TransactionState::Chargedback);
+        // Dispute histories are kept by the ledger rather than the store.
+        assert_eq!(ledger.history(TransactionId(2)).unwrap().disputes, 1);
+    }
+
+    #[test]
+    fn ledger_with_dense_accounts() {
+        let mut ledger = Ledger::with_stores(HashMap::new(), DenseAccountStore::new());
+        for (id, client) in [(1, 3), (2, 1), (3, 3)] {
+            ledger
+                .apply_transaction(
+                    TransactionId(id),
+                    &Transaction::new(ClientId(client), num!(10.0), Operation::Deposit),
+                )
+                .unwrap();
+        }
+        let accounts: Vec<_> = ledger
+            .accounts()
+            .map(|(client_id, account)| (*client_id, account.available()))
+            .collect();
+        assert_eq!(
+            accounts,
+            vec![(ClientId(1), num!(10.0)), (ClientId(3), num!(20.0))]
+        );
+        assert_eq!(ledger.into_iter().count(), 2);
+    }
+
+    #[test]
+    fn transaction_store_failure_is_reported() {
+        let store = FullStore {
+            transactions: HashMap::new(),
+            capacity: 1,
+            removals: true,
+        };
+        let mut ledger = Ledger::with_store(store);
+        let deposit = Transaction::new(ClientId(1), num!(10.0), Operation::Deposit);
+        assert!(ledger.apply_transaction(TransactionId(1), &deposit).is_ok());
+        let res = ledger.apply_transaction(TransactionId(2), &deposit);
+        assert_eq!(
+            res,
+            Err(TransactionError::Store(
+                TransactionId(2),
+                io::ErrorKind::StorageFull
+            ))
+        );
+        assert_eq!(res.unwrap_err().code(), "store_failure");
+        assert_eq!(ledger.sequence(), 1);
+        // The balance only reflects the transaction the store holds.
+        let account = ledger.accounts.get(&ClientId(1)).unwrap();
+        assert_eq!(account.available(), num!(10.0));
+        let withdrawal = Transaction::new(ClientId(1), num!(5.0), Operation::Withdrawal);
+        assert!(ledger
+            .apply_transaction(TransactionId(3), &withdrawal)
+            .is_err());
+        assert_eq!(
+            ledger.accounts.get(&ClientId(1)).unwrap().available(),
+            num!(10.0)
+        );
+    }
+
+    #[test]
+    fn rollback_failure_keeps_original_error() {
+        let store = FullStore {
+            transactions: HashMap::new(),
+            capacity: 1,
+            removals: false,
+        };
+        let mut ledger = Ledger::with_store(store);
+        let withdrawal = Transaction::new(ClientId(1), num!(5.0), Operation::Withdrawal);
+        let err = ledger
+            .apply_transaction(TransactionId(1), &withdrawal)
+            .unwrap_err();
+        let TransactionError::RollbackFailed(TransactionId(1), kind, original) = &err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(*kind, io::ErrorKind::PermissionDenied);
+        assert!(matches!(**original, TransactionError::AccountError(..)));
+        assert_eq!(err.code(), "account_underflow");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
@@ -1,10 +1,15 @@
 use super::TransactionResult;
 use crate::{
-    account::num, account::AccountError, account::ClientId, account::Number, ledger::Ledger,
-    transactions::Operation, transactions::Transaction, transactions::TransactionError,
-    transactions::TransactionId, transactions::TransactionState,
+    account::num, account::AccountError, account::ClientId, account::Currency, account::Number,
+    ledger::Applied, ledger::Ledger, ledger::LedgerEvent, ledger::LedgerObserver,
+    transactions::DisputePolicy, transactions::DisputeWindow, transactions::LockPolicy,
+    transactions::Operation, transactions::Precision, transactions::Rounding,
+    transactions::Transaction, transactions::TransactionError, transactions::TransactionId,
+    transactions::TransactionState,
 };
 
+use std::sync::Arc;
+
 type TransactionList = Vec<(TransactionId, Transaction)>;
 
 fn process_transactions<'a>(
@@ -70,6 +75,35 @@ fn cant_deposit_with_same_id() {
     );
 }
 
+#[test]
+fn cant_deposit_or_withdraw_zero() {
+    let mut ledger = Ledger::new().with_precision(Precision {
+        scale: 4,
+        rounding: Some(Rounding::Down),
+    });
+    for operation in [Operation::Deposit, Operation::Withdrawal] {
+        let res = ledger.apply_transaction(
+            TransactionId(1),
+            &Transaction::new(ClientId(1), Number::ZERO, operation),
+        );
+        assert_eq!(
+            res,
+            Err(TransactionError::InvalidAmount(
+                TransactionId(1),
+                Number::ZERO
+            ))
+        );
+    }
+    // Amounts rounded down to zero are rejected too.
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(0.00001), Operation::Deposit),
+    );
+    assert!(matches!(res, Err(TransactionError::InvalidAmount(_, _))));
+    assert!(ledger.accounts.is_empty());
+    assert_eq!(ledger.sequence(), 0);
+}
+
 //  WITHDRAWAL
 #[test]
 fn simple_withdrawal() {
@@ -272,10 +306,8 @@ fn cant_dispute_withdrawal() {
         TransactionId(2),
         &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
     );
-    assert_eq!(
-        res,
-        Err(TransactionError::AlreadyDisputed(TransactionId(2)))
-    );
+    assert_eq!(res, Err(TransactionError::NotDisputable(TransactionId(2))));
+    assert_eq!(res.unwrap_err().code(), "not_disputable");
     assert_eq!(
         ledger.accounts.get(&ClientId(1)).unwrap().available(),
         num!(30.0)
@@ -290,6 +322,125 @@ fn cant_dispute_withdrawal() {
     assert_eq!(transaction.state(), TransactionState::Ok);
 }
 
+fn withdrawal_disputes() -> DisputePolicy {
+    DisputePolicy {
+        withdrawals: true,
+        ..DisputePolicy::default()
+    }
+}
+
+#[test]
+fn dispute_withdrawal_with_policy() {
+    let mut ledger = Ledger::new().with_dispute_policy(withdrawal_disputes());
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Withdrawal),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+    ];
+    process_transactions(&mut ledger, &transactions)
+        .enumerate()
+        .for_each(|(i, res)| {
+            assert!(
+                res.is_ok(),
+                "transaction '{}' result is not ok: {:?}",
+                i,
+                res.unwrap_err()
+            )
+        });
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(30.0)
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().held(),
+        num!(20.0)
+    );
+    assert!(!ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    let transaction = ledger.transactions.get(&TransactionId(2)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Disputed);
+}
+
+#[test]
+fn resolve_disputed_withdrawal() {
+    let mut ledger = Ledger::new().with_dispute_policy(withdrawal_disputes());
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Withdrawal),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(30.0)
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().held(),
+        Number::ZERO
+    );
+    assert!(!ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    let transaction = ledger.transactions.get(&TransactionId(2)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Resolved);
+}
+
+#[test]
+fn chargeback_disputed_withdrawal() {
+    let mut ledger = Ledger::new().with_dispute_policy(withdrawal_disputes());
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Withdrawal),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Chargeback),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(50.0)
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().held(),
+        Number::ZERO
+    );
+    assert!(ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    let transaction = ledger.transactions.get(&TransactionId(2)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Chargedback);
+}
+
 // CHARGEBACK
 #[test]
 fn simple_chargeback() {
@@ -515,7 +666,7 @@ fn simple_resolve() {
     assert!(!ledger.accounts.get(&ClientId(1)).unwrap().locked());
     assert_eq!(ledger.transactions.len(), 2);
     let transaction = ledger.transactions.get(&TransactionId(2)).unwrap();
-    assert_eq!(transaction.state(), TransactionState::Ok);
+    assert_eq!(transaction.state(), TransactionState::Resolved);
 }
 
 #[test]
@@ -558,3 +709,1561 @@ fn cant_resolve_undisputed_transaction() {
     assert!(!ledger.accounts.get(&ClientId(1)).unwrap().locked());
     assert_eq!(ledger.transactions.len(), 1);
 }
+
+// LOCKED ACCOUNTS
+fn locked_account_ledger(lock_policy: LockPolicy) -> Ledger {
+    let mut ledger = Ledger::new().with_lock_policy(lock_policy);
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(40.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), num!(10.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Chargeback),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    assert!(ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    ledger
+}
+
+#[test]
+fn deposit_on_locked_account_with_permissive_policy() {
+    let mut ledger = locked_account_ledger(LockPolicy::permissive());
+    let res = ledger.apply_transaction(
+        TransactionId(4),
+        &Transaction::new(ClientId(1), num!(5.0), Operation::Deposit),
+    );
+    assert!(res.is_ok());
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(45.0)
+    );
+}
+
+#[test]
+fn cant_deposit_on_locked_account_by_default() {
+    let mut ledger = locked_account_ledger(LockPolicy::default());
+    let account = ledger.accounts.get(&ClientId(1)).unwrap().clone();
+    let res = ledger.apply_transaction(
+        TransactionId(4),
+        &Transaction::new(ClientId(1), num!(5.0), Operation::Deposit),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::AccountError(
+            ClientId(1),
+            AccountError::FrozenAccount(account.clone())
+        ))
+    );
+    assert_eq!(ledger.accounts.get(&ClientId(1)).unwrap(), &account);
+    assert_eq!(ledger.transactions.len(), 3);
+}
+
+#[test]
+fn cant_dispute_on_locked_account_by_default() {
+    let mut ledger = locked_account_ledger(LockPolicy::default());
+    let account = ledger.accounts.get(&ClientId(1)).unwrap().clone();
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::AccountError(
+            ClientId(1),
+            AccountError::FrozenAccount(account)
+        ))
+    );
+    let transaction = ledger.transactions.get(&TransactionId(1)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Ok);
+}
+
+#[test]
+fn resolve_on_locked_account_by_default() {
+    let mut ledger = locked_account_ledger(LockPolicy::default());
+    let res = ledger.apply_transaction(
+        TransactionId(3),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+    );
+    assert!(res.is_ok());
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(50.0)
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().held(),
+        Number::ZERO
+    );
+}
+
+#[test]
+fn unlock_account() {
+    let lock_policy = LockPolicy {
+        unlocks: true,
+        ..LockPolicy::default()
+    };
+    let mut ledger = locked_account_ledger(lock_policy);
+    let res = ledger.apply_transaction(
+        TransactionId(0),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Unlock),
+    );
+    assert!(res.is_ok());
+    assert!(!ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    let res = ledger.apply_transaction(
+        TransactionId(4),
+        &Transaction::new(ClientId(1), num!(5.0), Operation::Withdrawal),
+    );
+    assert!(res.is_ok());
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(35.0)
+    );
+}
+
+#[test]
+fn cant_unlock_without_policy() {
+    let mut ledger = locked_account_ledger(LockPolicy::default());
+    let res = ledger.apply_transaction(
+        TransactionId(0),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Unlock),
+    );
+    assert_eq!(res, Err(TransactionError::UnlockNotAllowed(ClientId(1))));
+    assert!(ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    assert!(ledger.unlock_account(ClientId(1)).is_ok());
+    assert!(!ledger.accounts.get(&ClientId(1)).unwrap().locked());
+}
+
+#[test]
+fn cant_unlock_unknown_account() {
+    let mut ledger = Ledger::new();
+    let res = ledger.unlock_account(ClientId(1));
+    assert_eq!(res, Err(TransactionError::UnknownClientId(ClientId(1))));
+    assert_eq!(ledger.accounts.len(), 0);
+}
+
+// CURRENCIES
+fn eur() -> Currency {
+    "EUR".parse().unwrap()
+}
+
+fn usd() -> Currency {
+    "USD".parse().unwrap()
+}
+
+#[test]
+fn balances_are_kept_per_currency() {
+    let mut ledger = Ledger::new();
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit).with_currency(eur()),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Deposit).with_currency(usd()),
+        ),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), num!(10.0), Operation::Withdrawal).with_currency(eur()),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.balance(eur()).available(), num!(40.0));
+    assert_eq!(account.balance(usd()).available(), num!(20.0));
+    assert_eq!(account.available(), Number::ZERO);
+    let res = ledger.apply_transaction(
+        TransactionId(4),
+        &Transaction::new(ClientId(1), num!(30.0), Operation::Withdrawal).with_currency(usd()),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::AccountError(
+            ClientId(1),
+            AccountError::Underflow {
+                available: num!(20.0),
+                held: Number::ZERO,
+                transaction_amount: num!(30.0)
+            }
+        ))
+    );
+}
+
+#[test]
+fn dispute_in_currency_of_deposit() {
+    let mut ledger = Ledger::new();
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit).with_currency(eur()),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Deposit).with_currency(eur()),
+        ),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute).with_currency(eur()),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.balance(eur()).available(), Number::ZERO);
+    assert_eq!(account.balance(eur()).held(), num!(70.0));
+}
+
+#[test]
+fn cant_dispute_in_other_currency() {
+    let mut ledger = Ledger::new();
+    let _ = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(50.0), Operation::Deposit).with_currency(eur()),
+    );
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute).with_currency(usd()),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::MismatchedCurrency(
+            TransactionId(1),
+            usd(),
+            eur()
+        ))
+    );
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.balance(eur()).available(), num!(50.0));
+    assert_eq!(account.balance(usd()).held(), Number::ZERO);
+    let transaction = ledger.transactions.get(&TransactionId(1)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Ok);
+}
+
+// SNAPSHOTS
+#[test]
+fn dispute_after_restoring_snapshot() {
+    let mut ledger = Ledger::new();
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(2), num!(20.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(2), Number::ZERO, Operation::Dispute),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    let mut snapshot = Vec::new();
+    ledger.save(&mut snapshot).unwrap();
+    let mut restored = Ledger::load(snapshot.as_slice()).unwrap();
+    assert_eq!(restored.accounts, ledger.accounts);
+    assert_eq!(restored.transactions, ledger.transactions);
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(2), Number::ZERO, Operation::Resolve),
+        ),
+    ];
+    let res = process_transactions(&mut restored, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    assert_eq!(
+        restored.accounts.get(&ClientId(1)).unwrap().held(),
+        num!(50.0)
+    );
+    assert_eq!(
+        restored.accounts.get(&ClientId(2)).unwrap().available(),
+        num!(20.0)
+    );
+    let res = restored.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(1.0), Operation::Deposit),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::RepeatedTransactionId(TransactionId(1)))
+    );
+}
+
+// DISPUTE WINDOW
+fn deposits(count: u32) -> TransactionList {
+    (1..=count)
+        .map(|id| {
+            (
+                TransactionId(id),
+                Transaction::new(ClientId(1), num!(10.0), Operation::Deposit),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn transactions_past_dispute_window_are_evicted() {
+    let mut ledger = Ledger::new().with_dispute_window(DisputeWindow::Transactions(2));
+    let res = process_transactions(&mut ledger, &deposits(3)).all(|res| res.is_ok());
+    assert!(res);
+    assert_eq!(ledger.transactions.len(), 2);
+    assert!(!ledger.transactions.contains_key(&TransactionId(1)));
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::DisputeWindowExpired(TransactionId(1)))
+    );
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(10.0), Operation::Deposit),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::RepeatedTransactionId(TransactionId(1)))
+    );
+    let res = ledger.apply_transaction(
+        TransactionId(2),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+    );
+    assert!(res.is_ok());
+}
+
+#[test]
+fn evicted_ids_take_bounded_memory() {
+    let mut ledger = Ledger::new().with_dispute_window(DisputeWindow::Transactions(10));
+    for id in 1..=1000 {
+        let res = ledger.apply_transaction(
+            TransactionId(id),
+            &Transaction::new(ClientId((id % 7) as u16), num!(1.0), Operation::Deposit),
+        );
+        assert!(res.is_ok());
+    }
+    assert_eq!(ledger.transactions.len(), 10);
+    assert_eq!(ledger.evicted.ranges(), 1);
+    let res = ledger.apply_transaction(
+        TransactionId(500),
+        &Transaction::new(ClientId(3), Number::ZERO, Operation::Dispute),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::DisputeWindowExpired(TransactionId(500)))
+    );
+}
+
+#[test]
+fn ledger_with_dispute_window_is_not_split() {
+    let ledger = Ledger::new().with_dispute_window(DisputeWindow::Transactions(1));
+    assert_eq!(ledger.split(4).len(), 1);
+}
+
+#[test]
+fn disputed_transactions_are_not_evicted() {
+    let mut ledger = Ledger::new().with_dispute_window(DisputeWindow::Transactions(1));
+    let mut transactions = deposits(1);
+    transactions.push((
+        TransactionId(1),
+        Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+    ));
+    transactions.extend(deposits(3).into_iter().skip(1));
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    assert!(ledger.transactions.contains_key(&TransactionId(1)));
+    assert!(!ledger.transactions.contains_key(&TransactionId(2)));
+    assert!(ledger.transactions.contains_key(&TransactionId(3)));
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+    );
+    assert!(res.is_ok());
+    // Once settled, it is evicted like any other transaction.
+    let res = process_transactions(
+        &mut ledger,
+        &vec![(
+            TransactionId(4),
+            Transaction::new(ClientId(1), num!(10.0), Operation::Deposit),
+        )],
+    )
+    .all(|res| res.is_ok());
+    assert!(res);
+    assert!(!ledger.transactions.contains_key(&TransactionId(1)));
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(40.0)
+    );
+}
+
+// TRANSFERS
+fn transfer(from: u16, to: u16, amount: Number) -> Transaction {
+    Transaction::new(ClientId(from), amount, Operation::Transfer).with_counterparty(ClientId(to))
+}
+
+fn funded_ledger() -> Ledger {
+    let mut ledger = Ledger::new();
+    let _ = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+    );
+    let _ = ledger.apply_transaction(
+        TransactionId(2),
+        &Transaction::new(ClientId(2), num!(5.0), Operation::Deposit),
+    );
+    ledger
+}
+
+#[test]
+fn transfer_between_accounts() {
+    let mut ledger = funded_ledger();
+    let res = ledger.apply_transaction(TransactionId(3), &transfer(1, 2, num!(20.0)));
+    assert!(res.is_ok());
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(30.0)
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(2)).unwrap().available(),
+        num!(25.0)
+    );
+    let res = ledger.apply_transaction(TransactionId(4), &transfer(2, 3, num!(25.0)));
+    assert!(res.is_ok());
+    assert_eq!(
+        ledger.accounts.get(&ClientId(3)).unwrap().available(),
+        num!(25.0)
+    );
+}
+
+#[test]
+fn failed_transfer_leaves_accounts_untouched() {
+    let mut ledger = funded_ledger();
+    let res = ledger.apply_transaction(TransactionId(3), &transfer(2, 1, num!(20.0)));
+    assert!(matches!(
+        res,
+        Err(TransactionError::AccountError(
+            ClientId(2),
+            AccountError::Underflow { .. }
+        ))
+    ));
+    let res = ledger.apply_transaction(TransactionId(3), &transfer(2, 2, num!(1.0)));
+    assert_eq!(
+        res,
+        Err(TransactionError::InvalidTransfer(TransactionId(3)))
+    );
+    let res = ledger.apply_transaction(
+        TransactionId(3),
+        &Transaction::new(ClientId(2), num!(1.0), Operation::Transfer),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::InvalidTransfer(TransactionId(3)))
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(50.0)
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(2)).unwrap().available(),
+        num!(5.0)
+    );
+    assert!(!ledger.transactions.contains_key(&TransactionId(3)));
+}
+
+#[test]
+fn cant_transfer_from_or_to_locked_account() {
+    let mut ledger = funded_ledger();
+    ledger
+        .accounts
+        .get_mut(&ClientId(2))
+        .unwrap()
+        .chargeback(Currency::NONE, Number::ZERO);
+    let res = ledger.apply_transaction(TransactionId(3), &transfer(1, 2, num!(10.0)));
+    assert!(matches!(
+        res,
+        Err(TransactionError::AccountError(
+            ClientId(2),
+            AccountError::FrozenAccount(_)
+        ))
+    ));
+    let res = ledger.apply_transaction(TransactionId(3), &transfer(2, 1, num!(1.0)));
+    assert!(matches!(
+        res,
+        Err(TransactionError::AccountError(
+            ClientId(2),
+            AccountError::FrozenAccount(_)
+        ))
+    ));
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().available(),
+        num!(50.0)
+    );
+}
+
+#[test]
+fn dispute_transfer() {
+    let mut ledger = funded_ledger();
+    let transactions: TransactionList = vec![
+        (TransactionId(3), transfer(1, 2, num!(20.0))),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    let receiver = ledger.accounts.get(&ClientId(2)).unwrap();
+    assert_eq!(receiver.available(), num!(5.0));
+    assert_eq!(receiver.held(), num!(20.0));
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().total(),
+        num!(30.0)
+    );
+    // Only the sender can dispute a transfer.
+    let res = ledger.apply_transaction(
+        TransactionId(3),
+        &Transaction::new(ClientId(2), Number::ZERO, Operation::Resolve),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::MismatchedClientId(
+            ClientId(2),
+            ClientId(1)
+        ))
+    );
+    let res = ledger.apply_transaction(
+        TransactionId(3),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+    );
+    assert!(res.is_ok());
+    let receiver = ledger.accounts.get(&ClientId(2)).unwrap();
+    assert_eq!(receiver.available(), num!(25.0));
+    assert_eq!(receiver.held(), Number::ZERO);
+}
+
+#[test]
+fn chargeback_transfer() {
+    let mut ledger = funded_ledger();
+    let transactions: TransactionList = vec![
+        (TransactionId(3), transfer(1, 2, num!(20.0))),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Chargeback),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    let sender = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(sender.available(), num!(50.0));
+    assert!(!sender.locked());
+    let receiver = ledger.accounts.get(&ClientId(2)).unwrap();
+    assert_eq!(receiver.total(), num!(5.0));
+    assert!(receiver.locked());
+    let transaction = ledger.transactions.get(&TransactionId(3)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Chargedback);
+}
+
+#[test]
+fn cant_transfer_across_shards() {
+    let mut shards = funded_ledger().split(2);
+    let res = shards[1].apply_transaction(TransactionId(3), &transfer(1, 2, num!(20.0)));
+    assert_eq!(
+        res,
+        Err(TransactionError::CrossShardTransfer(TransactionId(3)))
+    );
+    let res = shards[1].apply_transaction(TransactionId(3), &transfer(1, 3, num!(20.0)));
+    assert!(res.is_ok());
+}
+
+// PARTIAL DISPUTES
+fn dispute(amount: Number) -> (TransactionId, Transaction) {
+    (
+        TransactionId(1),
+        Transaction::new(ClientId(1), amount, Operation::Dispute),
+    )
+}
+
+fn balances(ledger: &Ledger) -> (Number, Number) {
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    (account.available(), account.held())
+}
+
+#[test]
+fn partial_dispute_and_resolve() {
+    let mut ledger = Ledger::new();
+    let transactions = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        dispute(num!(20.0)),
+    ];
+    assert!(process_transactions(&mut ledger, &transactions).all(|res| res.is_ok()));
+    assert_eq!(balances(&ledger), (num!(30.0), num!(20.0)));
+    let transaction = ledger.transactions.get(&TransactionId(1)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Disputed);
+    assert_eq!(transaction.disputed(), num!(20.0));
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+    );
+    assert!(res.is_ok());
+    assert_eq!(balances(&ledger), (num!(50.0), Number::ZERO));
+    let transaction = ledger.transactions.get(&TransactionId(1)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Resolved);
+    assert_eq!(transaction.disputed(), Number::ZERO);
+}
+
+#[test]
+fn partial_disputes_up_to_amount() {
+    let mut ledger = Ledger::new();
+    let transactions = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        dispute(num!(20.0)),
+        dispute(num!(40.0)),
+        dispute(num!(25.0)),
+        dispute(Number::ZERO),
+        dispute(num!(1.0)),
+    ];
+    let results: Vec<TransactionResult> =
+        process_transactions(&mut ledger, &transactions).collect();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            Err(TransactionError::ExcessDisputeAmount(
+                TransactionId(1),
+                num!(40.0)
+            )),
+            Ok(()),
+            // Disputes the remaining 5.0.
+            Ok(()),
+            Err(TransactionError::AlreadyDisputed(TransactionId(1))),
+        ]
+    );
+    assert_eq!(balances(&ledger), (Number::ZERO, num!(50.0)));
+}
+
+#[test]
+fn partial_chargeback() {
+    let mut ledger = Ledger::new();
+    let transactions = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        dispute(num!(20.0)),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Chargeback),
+        ),
+    ];
+    assert!(process_transactions(&mut ledger, &transactions).all(|res| res.is_ok()));
+    assert_eq!(balances(&ledger), (num!(30.0), Number::ZERO));
+    assert!(ledger.accounts.get(&ClientId(1)).unwrap().locked());
+    // The rest of the deposit stands.
+    let (id, transaction) = dispute(num!(10.0));
+    let res = ledger.apply_transaction(id, &transaction);
+    assert_eq!(
+        res,
+        Err(TransactionError::AlreadyDisputed(TransactionId(1)))
+    );
+}
+
+#[test]
+fn load_snapshot_without_disputed_amounts() {
+    let mut ledger = Ledger::new();
+    let transactions = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        dispute(Number::ZERO),
+    ];
+    assert!(process_transactions(&mut ledger, &transactions).all(|res| res.is_ok()));
+    let mut snapshot = Vec::new();
+    ledger.save(&mut snapshot).unwrap();
+    let snapshot = String::from_utf8(snapshot)
+        .unwrap()
+        .replace(",\"disputed\":\"50.0\"", "");
+    assert!(!snapshot.contains("disputed\""));
+    let mut ledger = Ledger::load(snapshot.as_bytes()).unwrap();
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve),
+    );
+    assert!(res.is_ok());
+    assert_eq!(balances(&ledger), (num!(50.0), Number::ZERO));
+}
+
+// DISPUTE LIFECYCLE
+const LIFECYCLE: [Operation; 5] = [
+    Operation::Dispute,
+    Operation::Resolve,
+    Operation::Chargeback,
+    Operation::Representment,
+    Operation::PreArbitration,
+];
+
+/// Ledger holding a deposit of 10 by client 1, taken through `operations`.
+fn lifecycle_ledger(operations: &[Operation]) -> Ledger {
+    lifecycle_ledger_of(Operation::Deposit, LockPolicy::default(), operations)
+}
+
+/// Ledger holding a `kind` transaction of 10 by client 1, with id 1, taken through `operations`.
+/// Withdrawals are made from a deposit of 20, so both leave client 1 with 10 available when
+/// undisputed.
+fn lifecycle_ledger_of(
+    kind: Operation,
+    lock_policy: LockPolicy,
+    operations: &[Operation],
+) -> Ledger {
+    let policy = DisputePolicy {
+        withdrawals: true,
+        ..DisputePolicy::default()
+    };
+    let mut ledger = Ledger::new()
+        .with_dispute_policy(policy)
+        .with_lock_policy(lock_policy);
+    if kind == Operation::Withdrawal {
+        let deposit = Transaction::new(ClientId(1), num!(20), Operation::Deposit);
+        assert!(ledger.apply_transaction(TransactionId(2), &deposit).is_ok());
+    }
+    let transaction = Transaction::new(ClientId(1), num!(10), kind);
+    assert!(ledger
+        .apply_transaction(TransactionId(1), &transaction)
+        .is_ok());
+    for operation in operations {
+        let transaction = Transaction::new(ClientId(1), Number::ZERO, *operation);
+        let res = ledger.apply_transaction(TransactionId(1), &transaction);
+        assert!(res.is_ok(), "{operation:?}: {res:?}");
+    }
+    ledger
+}
+
+#[test]
+fn reverse_chargeback_and_arbitrate() {
+    use Operation::{Chargeback, Dispute, PreArbitration, Representment, Resolve};
+
+    // Balances when the transaction stands, when it is charged back, and when it is disputed.
+    let stands = (num!(10), Number::ZERO);
+    for (kind, reversed, disputed) in [
+        (
+            Operation::Deposit,
+            (Number::ZERO, Number::ZERO),
+            (Number::ZERO, num!(10)),
+        ),
+        (
+            Operation::Withdrawal,
+            (num!(20), Number::ZERO),
+            (num!(10), num!(10)),
+        ),
+    ] {
+        // Representments and pre-arbitrations continue disputes that are already open, so even
+        // the default policy, which blocks new disputes, allows them on the account the
+        // chargeback locked.
+        let mut ledger = lifecycle_ledger_of(kind, LockPolicy::default(), &[Dispute, Chargeback]);
+        assert_eq!(balances(&ledger), reversed, "{kind:?}");
+        assert!(ledger.accounts.get(&ClientId(1)).unwrap().locked());
+        let steps = [
+            (Representment, TransactionState::Represented, stands, false),
+            (
+                PreArbitration,
+                TransactionState::PreArbitration,
+                disputed,
+                false,
+            ),
+            (Chargeback, TransactionState::Reversed, reversed, true),
+        ];
+        for (operation, state, expected, locked) in steps {
+            let transaction = Transaction::new(ClientId(1), Number::ZERO, operation);
+            let res = ledger.apply_transaction(TransactionId(1), &transaction);
+            assert!(res.is_ok(), "{kind:?} {operation:?}: {res:?}");
+            assert_eq!(ledger.transactions[&TransactionId(1)].state(), state);
+            assert_eq!(balances(&ledger), expected, "{kind:?} {operation:?}");
+            assert_eq!(ledger.accounts.get(&ClientId(1)).unwrap().locked(), locked);
+        }
+        let path = [Dispute, Chargeback, Representment, PreArbitration, Resolve];
+        let ledger = lifecycle_ledger_of(kind, LockPolicy::default(), &path);
+        assert_eq!(
+            ledger.transactions[&TransactionId(1)].state(),
+            TransactionState::Upheld
+        );
+        assert_eq!(balances(&ledger), stands, "{kind:?}");
+    }
+}
+
+#[test]
+fn arbitrated_transactions_are_evicted() {
+    use Operation::{Chargeback, Dispute, PreArbitration, Representment};
+
+    let path = [
+        Dispute,
+        Chargeback,
+        Representment,
+        PreArbitration,
+        Chargeback,
+    ];
+    let mut ledger = lifecycle_ledger(&path).with_dispute_window(DisputeWindow::Transactions(1));
+    ledger.window.push_back(TransactionId(1));
+    let deposit = Transaction::new(ClientId(2), num!(5), Operation::Deposit);
+    assert!(ledger.apply_transaction(TransactionId(2), &deposit).is_ok());
+    assert_eq!(ledger.window, vec![TransactionId(2)]);
+    assert!(ledger.evicted.contains(&TransactionId(1)));
+}
+
+#[test]
+fn representment_keeps_lock_of_other_chargebacks() {
+    use Operation::{Chargeback, Dispute, Representment};
+
+    // The second deposit and its dispute need a policy allowing them on the locked account.
+    let mut ledger = lifecycle_ledger_of(
+        Operation::Deposit,
+        LockPolicy::permissive(),
+        &[Dispute, Chargeback],
+    );
+    let deposit = Transaction::new(ClientId(1), num!(5), Operation::Deposit);
+    assert!(ledger.apply_transaction(TransactionId(2), &deposit).is_ok());
+    for operation in [Dispute, Chargeback, Representment] {
+        let id = match operation {
+            Representment => TransactionId(1),
+            _ => TransactionId(2),
+        };
+        let transaction = Transaction::new(ClientId(1), Number::ZERO, operation);
+        assert!(ledger.apply_transaction(id, &transaction).is_ok());
+    }
+    // The chargeback of the second deposit still stands.
+    assert!(ledger.accounts.get(&ClientId(1)).unwrap().locked());
+}
+
+#[test]
+fn represent_transfer() {
+    let lifecycle = [
+        Operation::Dispute,
+        Operation::Chargeback,
+        Operation::Representment,
+    ];
+    let mut ledger = funded_ledger();
+    let mut transactions: TransactionList = vec![(TransactionId(3), transfer(1, 2, num!(20.0)))];
+    assert!(process_transactions(&mut ledger, &transactions).all(|res| res.is_ok()));
+    let after_transfer: Vec<_> = ledger.accounts().map(|(_, a)| a.clone()).collect();
+    transactions = lifecycle
+        .into_iter()
+        .map(|operation| {
+            (
+                TransactionId(3),
+                Transaction::new(ClientId(1), Number::ZERO, operation),
+            )
+        })
+        .collect();
+    assert!(process_transactions(&mut ledger, &transactions).all(|res| res.is_ok()));
+    // The funds are back with the receiver, whose account is no longer locked.
+    let accounts: Vec<_> = ledger.accounts().map(|(_, a)| a.clone()).collect();
+    for (account, expected) in accounts.iter().zip(&after_transfer) {
+        assert_eq!(
+            account.balances().collect::<Vec<_>>(),
+            expected.balances().collect::<Vec<_>>()
+        );
+        assert!(!account.locked());
+    }
+}
+
+#[test]
+fn illegal_lifecycle_transitions_are_rejected() {
+    use Operation::{Chargeback, Dispute, PreArbitration, Representment, Resolve};
+
+    let paths: [(&[Operation], TransactionState); 8] = [
+        (&[], TransactionState::Ok),
+        (&[Dispute], TransactionState::Disputed),
+        (&[Dispute, Resolve], TransactionState::Resolved),
+        (&[Dispute, Chargeback], TransactionState::Chargedback),
+        (
+            &[Dispute, Chargeback, Representment],
+            TransactionState::Represented,
+        ),
+        (
+            &[Dispute, Chargeback, Representment, PreArbitration],
+            TransactionState::PreArbitration,
+        ),
+        (
+            &[Dispute, Chargeback, Representment, PreArbitration, Resolve],
+            TransactionState::Upheld,
+        ),
+        (
+            &[
+                Dispute,
+                Chargeback,
+                Representment,
+                PreArbitration,
+                Chargeback,
+            ],
+            TransactionState::Reversed,
+        ),
+    ];
+    for (kind, (path, state)) in [Operation::Deposit, Operation::Withdrawal]
+        .into_iter()
+        .flat_map(|kind| paths.map(|path| (kind, path)))
+    {
+        for operation in LIFECYCLE {
+            if state.transition(operation).is_some() {
+                continue;
+            }
+            let mut ledger = lifecycle_ledger_of(kind, LockPolicy::default(), path);
+            assert_eq!(ledger.transactions[&TransactionId(1)].state(), state);
+            let accounts: Vec<_> = ledger.accounts().map(|(_, a)| a.clone()).collect();
+            let transaction = Transaction::new(ClientId(1), Number::ZERO, operation);
+            let res = ledger.apply_transaction(TransactionId(1), &transaction);
+            let expected = match operation {
+                Dispute => TransactionError::AlreadyDisputed(TransactionId(1)),
+                Resolve | Chargeback => TransactionError::UndisputedTransaction(TransactionId(1)),
+                _ => TransactionError::InvalidTransition(TransactionId(1), state, operation),
+            };
+            assert_eq!(res, Err(expected), "{operation:?} in {state:?} of {kind:?}");
+            assert_eq!(ledger.transactions[&TransactionId(1)].state(), state);
+            let after: Vec<_> = ledger.accounts().map(|(_, a)| a.clone()).collect();
+            assert_eq!(after, accounts);
+        }
+    }
+}
+
+// DISPUTE HISTORY
+#[test]
+fn record_dispute_history() {
+    use Operation::{Dispute, Resolve};
+
+    let ledger = lifecycle_ledger(&[Dispute, Resolve, Dispute]);
+    let transaction = ledger.transactions[&TransactionId(1)];
+    assert_eq!(transaction.state(), TransactionState::Disputed);
+    // Records without a reference are identified by the ledger sequence number.
+    let history = ledger.history(TransactionId(1)).unwrap();
+    assert_eq!(history.disputes, 2);
+    let events: Vec<_> = history
+        .events()
+        .map(|event| (event.operation, event.reference))
+        .collect();
+    assert_eq!(events, vec![(Dispute, 1), (Resolve, 2), (Dispute, 3)]);
+    assert_eq!(history.last_dispute(), Some(3));
+    assert_eq!(history.last_resolve(), Some(2));
+}
+
+#[test]
+fn dispute_history_keeps_latest_references() {
+    use Operation::{Dispute, Resolve};
+
+    let mut ledger = lifecycle_ledger(&[]);
+    for reference in 100..110 {
+        let operation = [Dispute, Resolve][reference as usize % 2];
+        let transaction =
+            Transaction::new(ClientId(1), Number::ZERO, operation).with_reference(reference);
+        assert!(ledger
+            .apply_transaction(TransactionId(1), &transaction)
+            .is_ok());
+    }
+    let history = ledger.history(TransactionId(1)).unwrap();
+    assert_eq!(history.disputes, 5);
+    let references: Vec<_> = history.events().map(|event| event.reference).collect();
+    assert_eq!(references, (102..110).collect::<Vec<_>>());
+}
+
+#[test]
+fn dispute_history_is_kept_apart_from_transactions() {
+    // Transactions stay small, as histories are only kept for disputed transactions.
+    assert!(std::mem::size_of::<Transaction>() <= 80);
+
+    let mut ledger = lifecycle_ledger(&[]);
+    assert_eq!(ledger.history(TransactionId(1)), None);
+    let dispute = Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute);
+    let unknown = Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve);
+    let batch = [(TransactionId(1), dispute), (TransactionId(9), unknown)];
+    assert!(ledger.apply_batch(&batch).is_err());
+    assert_eq!(ledger.history(TransactionId(1)), None);
+    assert!(ledger.apply_batch(&batch[..1]).is_ok());
+    assert_eq!(ledger.history(TransactionId(1)).unwrap().disputes, 1);
+}
+
+#[test]
+fn cant_dispute_past_limit() {
+    let mut ledger = lifecycle_ledger(&[]).with_dispute_policy(DisputePolicy {
+        max_disputes: Some(2),
+        ..DisputePolicy::default()
+    });
+    let dispute = Transaction::new(ClientId(1), num!(4), Operation::Dispute);
+    let resolve = Transaction::new(ClientId(1), Number::ZERO, Operation::Resolve);
+    let transactions = vec![
+        (TransactionId(1), dispute),
+        (TransactionId(1), dispute),
+        (TransactionId(1), resolve),
+        (TransactionId(1), dispute),
+    ];
+    let results: Vec<TransactionResult> =
+        process_transactions(&mut ledger, &transactions).collect();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            Err(TransactionError::DisputeLimitReached(TransactionId(1))),
+        ]
+    );
+    assert_eq!(balances(&ledger), (num!(10), Number::ZERO));
+}
+
+#[test]
+fn cant_dispute_after_final_resolve() {
+    use Operation::{Dispute, Resolve};
+
+    let mut ledger = lifecycle_ledger(&[Dispute, Resolve]).with_dispute_policy(DisputePolicy {
+        final_resolves: true,
+        ..DisputePolicy::default()
+    });
+    let dispute = Transaction::new(ClientId(1), Number::ZERO, Dispute);
+    let res = ledger.apply_transaction(TransactionId(1), &dispute);
+    assert_eq!(res, Err(TransactionError::FinalResolve(TransactionId(1))));
+    assert_eq!(res.unwrap_err().code(), "final_resolve");
+    assert_eq!(balances(&ledger), (num!(10), Number::ZERO));
+}
+
+// PRECISION
+#[test]
+fn cant_deposit_amount_with_excess_precision() {
+    let mut ledger = Ledger::new();
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(1.00005), Operation::Deposit),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::ExcessPrecision(
+            TransactionId(1),
+            num!(1.00005)
+        ))
+    );
+    assert!(ledger.accounts.is_empty());
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(1.00010), Operation::Deposit),
+    );
+    assert!(res.is_ok());
+}
+
+#[test]
+fn round_amount_with_excess_precision() {
+    let rounded = |scale, rounding| {
+        let mut ledger = Ledger::new().with_precision(Precision {
+            scale,
+            rounding: Some(rounding),
+        });
+        let res = ledger.apply_transaction(
+            TransactionId(1),
+            &Transaction::new(ClientId(1), num!(2.00025), Operation::Deposit),
+        );
+        assert!(res.is_ok());
+        ledger.transactions.get(&TransactionId(1)).unwrap().amount()
+    };
+    assert_eq!(rounded(4, Rounding::HalfEven), num!(2.0002));
+    assert_eq!(rounded(4, Rounding::HalfUp), num!(2.0003));
+    assert_eq!(rounded(4, Rounding::Down), num!(2.0002));
+    assert_eq!(rounded(4, Rounding::Up), num!(2.0003));
+    assert_eq!(rounded(2, Rounding::HalfUp), num!(2.00));
+}
+
+// TIMESTAMPS
+fn deposit_at(id: u32, timestamp: u64) -> (TransactionId, Transaction) {
+    (
+        TransactionId(id),
+        Transaction::new(ClientId(1), Number::ONE, Operation::Deposit).with_timestamp(timestamp),
+    )
+}
+
+#[test]
+fn cant_apply_transaction_older_than_latest() {
+    let mut ledger = Ledger::new();
+    let transactions = vec![
+        deposit_at(1, 100),
+        deposit_at(2, 100),
+        deposit_at(3, 99),
+        // Transactions without a timestamp aren't checked.
+        (
+            TransactionId(4),
+            Transaction::new(ClientId(1), Number::ONE, Operation::Deposit),
+        ),
+    ];
+    let results: Vec<TransactionResult> =
+        process_transactions(&mut ledger, &transactions).collect();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            Err(TransactionError::OutOfOrder(TransactionId(3), 99)),
+            Ok(()),
+        ]
+    );
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.available(), num!(3));
+    assert_eq!(account.timestamp(), Some(100));
+}
+
+#[test]
+fn apply_transaction_within_timestamp_tolerance() {
+    let mut ledger = Ledger::new().with_timestamp_tolerance(10);
+    let transactions = vec![deposit_at(1, 100), deposit_at(2, 90), deposit_at(3, 89)];
+    let results: Vec<TransactionResult> =
+        process_transactions(&mut ledger, &transactions).collect();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            Err(TransactionError::OutOfOrder(TransactionId(3), 89)),
+        ]
+    );
+    // The latest timestamp doesn't go back.
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.timestamp(), Some(100));
+}
+
+#[test]
+fn failed_batch_restores_timestamp() {
+    let mut ledger = Ledger::new();
+    let (id, transaction) = deposit_at(1, 100);
+    let _ = ledger.apply_transaction(id, &transaction);
+    let batch: TransactionList = vec![deposit_at(2, 200), deposit_at(1, 300)];
+    assert!(ledger.apply_batch(&batch).is_err());
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.timestamp(), Some(100));
+}
+
+// ORPHANS
+#[test]
+fn apply_dispute_before_its_deposit() {
+    let mut ledger = Ledger::new().with_orphan_window(2);
+    let dispute = Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute);
+    let transactions = vec![
+        (TransactionId(1), dispute),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+    ];
+    assert!(process_transactions(&mut ledger, &transactions).all(|res| res.is_ok()));
+    assert_eq!(ledger.sequence(), 2);
+    assert!(!ledger.is_orphan(TransactionId(1)));
+    assert_eq!(
+        ledger.take_settled_orphans(),
+        vec![(TransactionId(1), dispute, Ok(()))]
+    );
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().held(),
+        num!(50.0)
+    );
+}
+
+#[test]
+fn orphan_expires() {
+    let mut ledger = Ledger::new().with_orphan_window(1);
+    let dispute = Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute);
+    let res = ledger.apply_transaction(TransactionId(9), &dispute);
+    assert!(res.is_ok());
+    assert!(ledger.is_orphan(TransactionId(9)));
+    let (id, deposit) = deposit_at(1, 1);
+    let _ = ledger.apply_transaction(id, &deposit);
+    assert!(ledger.take_settled_orphans().is_empty());
+    let (id, deposit) = deposit_at(2, 2);
+    let _ = ledger.apply_transaction(id, &deposit);
+    assert_eq!(
+        ledger.take_settled_orphans(),
+        vec![(
+            TransactionId(9),
+            dispute,
+            Err(TransactionError::OrphanExpired(TransactionId(9)))
+        )]
+    );
+    assert!(!ledger.is_orphan(TransactionId(9)));
+}
+
+#[test]
+fn orphans_follow_their_shard() {
+    let mut ledger = Ledger::new().with_orphan_window(2);
+    for client in [1, 2] {
+        let dispute = Transaction::new(ClientId(client), Number::ZERO, Operation::Dispute);
+        let res = ledger.apply_transaction(TransactionId(client as u32), &dispute);
+        assert!(res.is_ok());
+    }
+    let mut shards = ledger.split(2);
+    for (shard, client) in shards.iter_mut().zip([2, 1]) {
+        assert!(shard.is_orphan(TransactionId(client)));
+        let deposit = Transaction::new(ClientId(client as u16), Number::ONE, Operation::Deposit);
+        let res = shard.apply_transaction(TransactionId(client), &deposit);
+        assert!(res.is_ok());
+        assert_eq!(shard.take_settled_orphans().len(), 1);
+    }
+}
+
+// BATCHES
+#[test]
+fn apply_batch() {
+    let mut ledger = Ledger::new();
+    let batch: TransactionList = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(2), num!(20.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+    ];
+    assert!(ledger.apply_batch(&batch).is_ok());
+    assert_eq!(ledger.sequence(), 3);
+    assert_eq!(
+        ledger.accounts.get(&ClientId(1)).unwrap().held(),
+        num!(50.0)
+    );
+}
+
+#[test]
+fn failed_batch_is_rolled_back() {
+    let mut ledger = Ledger::new();
+    let _ = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+    );
+    let batch: TransactionList = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(2), num!(20.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(3),
+            Transaction::new(ClientId(1), num!(10.0), Operation::Withdrawal),
+        ),
+    ];
+    let res = ledger.apply_batch(&batch);
+    let (index, err) = res.unwrap_err();
+    assert_eq!(index, 2);
+    assert_eq!(err.code(), "account_underflow");
+    assert_eq!(ledger.sequence(), 1);
+    let account = ledger.accounts.get(&ClientId(1)).unwrap();
+    assert_eq!(account.available(), num!(50.0));
+    assert_eq!(account.held(), Number::ZERO);
+    assert!(!ledger.accounts.contains_key(&ClientId(2)));
+    assert_eq!(ledger.transactions.len(), 1);
+    let transaction = ledger.transactions.get(&TransactionId(1)).unwrap();
+    assert_eq!(transaction.state(), TransactionState::Ok);
+    // Ids used by the failed batch are free again.
+    let res = ledger.apply_transaction(
+        TransactionId(2),
+        &Transaction::new(ClientId(2), num!(20.0), Operation::Deposit),
+    );
+    assert!(res.is_ok());
+}
+
+#[test]
+fn failed_batch_restores_evicted_transactions() {
+    let mut ledger = Ledger::new().with_dispute_window(DisputeWindow::Transactions(1));
+    let _ = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+    );
+    let batch: TransactionList = vec![
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(20.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+    ];
+    let (index, err) = ledger.apply_batch(&batch).unwrap_err();
+    assert_eq!(index, 1);
+    assert_eq!(
+        err,
+        TransactionError::DisputeWindowExpired(TransactionId(1))
+    );
+    assert_eq!(ledger.window, vec![TransactionId(1)]);
+    assert!(ledger.evicted.is_empty());
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+    );
+    assert!(res.is_ok());
+}
+
+// SHARDS
+#[test]
+fn split_and_merge_ledger() {
+    let mut ledger = Ledger::new();
+    let transactions: Vec<(TransactionId, Transaction)> = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(2), num!(20.0), Operation::Deposit),
+        ),
+    ];
+    let res = process_transactions(&mut ledger, &transactions).all(|res| res.is_ok());
+    assert!(res);
+    let mut shards = ledger.split(2);
+    assert_eq!(shards[0].accounts.len(), 1);
+    assert!(shards[0].accounts.contains_key(&ClientId(2)));
+    assert!(shards[1].transactions.contains_key(&TransactionId(1)));
+    let res = shards[1].apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+    );
+    assert!(res.is_ok());
+    let res = shards[0].apply_transaction(
+        TransactionId(3),
+        &Transaction::new(ClientId(4), num!(5.0), Operation::Deposit),
+    );
+    assert!(res.is_ok());
+    let mut shards = shards.into_iter();
+    let mut merged = shards.next().unwrap();
+    shards.for_each(|shard| merged.merge(shard));
+    assert_eq!(merged.sequence(), 4);
+    assert_eq!(merged.accounts.len(), 3);
+    assert_eq!(merged.transactions.len(), 3);
+    assert_eq!(
+        merged.accounts.get(&ClientId(1)).unwrap().held(),
+        num!(50.0)
+    );
+}
+
+#[test]
+fn transaction_ids_are_unique_across_shards() {
+    let mut ledger = Ledger::new();
+    let _ = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(10.0), Operation::Deposit),
+    );
+    let mut shards = ledger.split(2);
+    let res = shards[0].apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(2), num!(10.0), Operation::Deposit),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::RepeatedTransactionId(TransactionId(1)))
+    );
+    let res = shards[1].apply_transaction(
+        TransactionId(2),
+        &Transaction::new(ClientId(3), num!(20.0), Operation::Withdrawal),
+    );
+    assert!(res.is_err());
+    // A rejected transaction doesn't claim its id.
+    let res = shards[0].apply_transaction(
+        TransactionId(2),
+        &Transaction::new(ClientId(2), num!(10.0), Operation::Deposit),
+    );
+    assert!(res.is_ok());
+    let res = shards[1].apply_transaction(
+        TransactionId(2),
+        &Transaction::new(ClientId(3), num!(10.0), Operation::Deposit),
+    );
+    assert_eq!(
+        res,
+        Err(TransactionError::RepeatedTransactionId(TransactionId(2)))
+    );
+}
+
+// OBSERVER
+#[derive(Default)]
+struct Recorder(std::sync::Mutex<Vec<LedgerEvent>>);
+
+impl LedgerObserver for Recorder {
+    fn on_event(&self, event: &LedgerEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+fn observed_ledger() -> (Ledger, Arc<Recorder>) {
+    let recorder = Arc::new(Recorder::default());
+    (Ledger::new().with_observer(recorder.clone()), recorder)
+}
+
+#[test]
+fn observe_balance_changes() {
+    let (mut ledger, recorder) = observed_ledger();
+    let transactions: TransactionList = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Dispute),
+        ),
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), Number::ZERO, Operation::Chargeback),
+        ),
+        (
+            TransactionId(2),
+            Transaction::new(ClientId(1), num!(10.0), Operation::Withdrawal),
+        ),
+    ];
+    process_transactions(&mut ledger, &transactions).for_each(drop);
+    let events = recorder.0.lock().unwrap();
+    let [LedgerEvent::Deposited(deposited), LedgerEvent::Disputed(disputed), LedgerEvent::ChargedBack(charged_back), LedgerEvent::AccountLocked(ClientId(1)), LedgerEvent::Rejected {
+        transaction_id: TransactionId(2),
+        error: TransactionError::AccountError(ClientId(1), _),
+        ..
+    }] = &events[..]
+    else {
+        panic!("unexpected events: {:?}", events);
+    };
+    let change = |applied: &Applied| {
+        assert_eq!(applied.changes.len(), 1);
+        let change = applied.changes[0];
+        assert_eq!(change.client_id, ClientId(1));
+        let (before, after) = (change.before, change.after);
+        (
+            before.available(),
+            before.held(),
+            after.available(),
+            after.held(),
+        )
+    };
+    assert_eq!(change(deposited), (num!(0), num!(0), num!(50), num!(0)));
+    assert_eq!(change(disputed), (num!(50), num!(0), num!(0), num!(50)));
+    assert_eq!(change(charged_back), (num!(0), num!(50), num!(0), num!(0)));
+}
+
+#[test]
+fn observe_transfer_of_both_clients() {
+    let (mut ledger, recorder) = observed_ledger();
+    let transactions: TransactionList = vec![
+        (
+            TransactionId(1),
+            Transaction::new(ClientId(1), num!(50.0), Operation::Deposit),
+        ),
+        (TransactionId(2), transfer(1, 2, num!(20.0))),
+    ];
+    process_transactions(&mut ledger, &transactions).for_each(drop);
+    let events = recorder.0.lock().unwrap();
+    let LedgerEvent::Transferred(transferred) = &events[1] else {
+        panic!("unexpected event: {:?}", events[1]);
+    };
+    let totals: Vec<_> = transferred
+        .changes
+        .iter()
+        .map(|change| {
+            (
+                change.client_id,
+                change.before.total(),
+                change.after.total(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        totals,
+        vec![
+            (ClientId(1), num!(50), num!(30)),
+            (ClientId(2), num!(0), num!(20)),
+        ]
+    );
+}
+
+#[test]
+fn observe_batch_once_applied() {
+    let (mut ledger, recorder) = observed_ledger();
+    let deposit = |id, amount| {
+        (
+            TransactionId(id),
+            Transaction::new(ClientId(1), amount, Operation::Deposit),
+        )
+    };
+    let withdrawal = Transaction::new(ClientId(1), num!(100.0), Operation::Withdrawal);
+    let res = ledger.apply_batch(&[deposit(1, num!(10.0)), (TransactionId(2), withdrawal)]);
+    assert!(res.is_err());
+    {
+        let events = recorder.0.lock().unwrap();
+        assert!(
+            matches!(
+                &events[..],
+                [LedgerEvent::Rejected {
+                    transaction_id: TransactionId(2),
+                    ..
+                }]
+            ),
+            "unexpected events: {:?}",
+            events
+        );
+    }
+    let res = ledger.apply_batch(&[deposit(1, num!(10.0)), deposit(3, num!(5.0))]);
+    assert!(res.is_ok());
+    let events = recorder.0.lock().unwrap();
+    assert_eq!(events.len(), 3);
+    assert!(matches!(
+        &events[1..],
+        [LedgerEvent::Deposited(_), LedgerEvent::Deposited(_)]
+    ));
+}
+
+// ERRORS
+#[test]
+fn account_error_is_error_source() {
+    use std::error::Error;
+
+    let mut ledger = Ledger::new();
+    let res = ledger.apply_transaction(
+        TransactionId(1),
+        &Transaction::new(ClientId(1), num!(20.0), Operation::Withdrawal),
+    );
+    let err = res.unwrap_err();
+    assert_eq!(err.code(), "account_underflow");
+    assert_eq!(err.to_string(), "operation failed on account of client 1");
+    assert_eq!(
+        err.source().unwrap().to_string(),
+        "insufficient funds for amount 20.0 (available 0, held 0)"
+    );
+}
@@ -1,4 +1,6 @@
 pub mod account;
 pub mod app;
+pub mod journal;
 pub mod ledger;
+pub mod rejects;
 pub mod transactions;
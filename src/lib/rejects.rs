@@ -0,0 +1,79 @@
+use std::{error, io};
+
+use super::transactions::TransactionError;
+
+/// Joins the messages of an error and all of its sources.
+pub fn error_chain(err: &dyn error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+#[derive(serde::Serialize)]
+struct CsvRejectRecord<'a> {
+    line: u64,
+    record: &'a str,
+    code: &'static str,
+    message: String,
+}
+
+/// Writes one CSV row for every transaction rejected by the ledger.
+///
+/// Each row holds the input line number, the original record as it was read, and the error
+/// code and message describing why it was rejected.
+pub struct RejectWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: io::Write> RejectWriter<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(output),
+        }
+    }
+
+    /// Reports a record that was parsed but rejected by the ledger.
+    pub fn write_error(
+        &mut self,
+        line: u64,
+        record: &csv::StringRecord,
+        err: &TransactionError,
+    ) -> csv::Result<()> {
+        self.write(line, record, err.code(), error_chain(err))
+    }
+
+    /// Reports a record that couldn't be parsed into a transaction.
+    pub fn write_malformed(
+        &mut self,
+        line: u64,
+        record: &csv::StringRecord,
+        message: String,
+    ) -> csv::Result<()> {
+        self.write(line, record, "malformed_record", message)
+    }
+
+    fn write(
+        &mut self,
+        line: u64,
+        record: &csv::StringRecord,
+        code: &'static str,
+        message: String,
+    ) -> csv::Result<()> {
+        let record = record.iter().collect::<Vec<&str>>().join(",");
+        self.writer.serialize(CsvRejectRecord {
+            line,
+            record: &record,
+            code,
+            message,
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -1,10 +1,20 @@
-use super::account::{Account, ClientId, Number};
+use std::{error, fmt, io};
+
+use rust_decimal::RoundingStrategy;
+
+use super::account::{Account, AccountResult, ClientId, Currency, Number, Timestamp};
 use crate::account::AccountError;
 
-#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TransactionId(pub u32);
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum TransactionError {
     RepeatedTransactionId(TransactionId),
     UnknownTransactionId(TransactionId),
@@ -14,32 +24,525 @@ pub enum TransactionError {
     UndisputedTransaction(TransactionId),
     AccountError(ClientId, AccountError),
     InvalidAmount(TransactionId, Number),
+    MismatchedCurrency(TransactionId, Currency, Currency),
+    DisputeWindowExpired(TransactionId),
+    /// The transaction store failed while handling a transaction. The ledger may have been
+    /// partially updated and should be rebuilt, for instance from its journal.
+    Store(TransactionId, io::ErrorKind),
+    /// The transaction store failed to remove a transaction the ledger rejected, which it may
+    /// still hold. The error that rejected it is kept as the source, and gives the code.
+    RollbackFailed(TransactionId, io::ErrorKind, Box<TransactionError>),
+    /// A transfer without a receiving client, or to the sending client itself.
+    InvalidTransfer(TransactionId),
+    /// A transfer between clients handled by different shards of a split ledger.
+    CrossShardTransfer(TransactionId),
+    /// An amount with more decimal places than the ledger accepts.
+    ExcessPrecision(TransactionId, Number),
+    /// A deposit, withdrawal or transfer without an amount.
+    MissingAmount(TransactionId),
+    /// A resolve, chargeback or unlock with an amount.
+    UnexpectedAmount(TransactionId),
+    /// A dispute for more than the part of a transaction that isn't disputed yet.
+    ExcessDisputeAmount(TransactionId, Number),
+    /// A transaction older than the latest transaction of its client, beyond the tolerance of
+    /// the ledger.
+    OutOfOrder(TransactionId, Timestamp),
+    /// A dispute, resolve or chargeback whose transaction didn't arrive within the orphan window
+    /// of the ledger.
+    OrphanExpired(TransactionId),
+    /// An operation the dispute lifecycle doesn't allow in the current state of its
+    /// transaction.
+    InvalidTransition(TransactionId, TransactionState, Operation),
+    /// A dispute of a transaction that was disputed as many times as the policy allows.
+    DisputeLimitReached(TransactionId),
+    /// A dispute, resolve or chargeback of a transaction the dispute policy doesn't allow to be
+    /// disputed, such as a withdrawal.
+    NotDisputable(TransactionId),
+    /// An unlock record while the lock policy doesn't allow them.
+    UnlockNotAllowed(ClientId),
+    /// A dispute of a resolved transaction while the dispute policy makes resolves final.
+    FinalResolve(TransactionId),
 }
 pub type TransactionResult = Result<(), TransactionError>;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+impl TransactionError {
+    /// Stable identifier of the error kind, suitable for logs and reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::RepeatedTransactionId(_) => "repeated_transaction_id",
+            TransactionError::UnknownTransactionId(_) => "unknown_transaction_id",
+            TransactionError::UnknownClientId(_) => "unknown_client_id",
+            TransactionError::MismatchedClientId(_, _) => "mismatched_client_id",
+            TransactionError::AlreadyDisputed(_) => "already_disputed",
+            TransactionError::UndisputedTransaction(_) => "undisputed_transaction",
+            TransactionError::AccountError(_, err) => err.code(),
+            TransactionError::InvalidAmount(_, _) => "invalid_amount",
+            TransactionError::MismatchedCurrency(_, _, _) => "mismatched_currency",
+            TransactionError::DisputeWindowExpired(_) => "dispute_window_expired",
+            TransactionError::Store(_, _) => "store_failure",
+            TransactionError::RollbackFailed(_, _, err) => err.code(),
+            TransactionError::InvalidTransfer(_) => "invalid_transfer",
+            TransactionError::CrossShardTransfer(_) => "cross_shard_transfer",
+            TransactionError::ExcessPrecision(_, _) => "excess_precision",
+            TransactionError::MissingAmount(_) => "missing_amount",
+            TransactionError::UnexpectedAmount(_) => "unexpected_amount",
+            TransactionError::ExcessDisputeAmount(_, _) => "excess_dispute_amount",
+            TransactionError::OutOfOrder(_, _) => "out_of_order",
+            TransactionError::OrphanExpired(_) => "orphan_expired",
+            TransactionError::InvalidTransition(_, _, _) => "invalid_transition",
+            TransactionError::DisputeLimitReached(_) => "dispute_limit_reached",
+            TransactionError::NotDisputable(_) => "not_disputable",
+            TransactionError::UnlockNotAllowed(_) => "unlock_not_allowed",
+            TransactionError::FinalResolve(_) => "final_resolve",
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::RepeatedTransactionId(id) => {
+                write!(f, "transaction {id} was already processed")
+            }
+            TransactionError::UnknownTransactionId(id) => write!(f, "unknown transaction {id}"),
+            TransactionError::UnknownClientId(client) => write!(f, "unknown client {client}"),
+            TransactionError::MismatchedClientId(client, owner) => write!(
+                f,
+                "client {client} can't operate on a transaction of client {owner}"
+            ),
+            TransactionError::AlreadyDisputed(id) => {
+                write!(f, "transaction {id} can't be disputed")
+            }
+            TransactionError::UndisputedTransaction(id) => {
+                write!(f, "transaction {id} is not under dispute")
+            }
+            TransactionError::AccountError(client, _) => {
+                write!(f, "operation failed on account of client {client}")
+            }
+            TransactionError::InvalidAmount(id, amount) => {
+                write!(f, "invalid amount {amount} in transaction {id}")
+            }
+            TransactionError::MismatchedCurrency(id, currency, original) => write!(
+                f,
+                "currency {currency} doesn't match currency {original} of transaction {id}"
+            ),
+            TransactionError::DisputeWindowExpired(id) => {
+                write!(f, "transaction {id} is past the dispute window")
+            }
+            TransactionError::Store(id, kind) => {
+                write!(f, "transaction store failed on transaction {id}: {kind}")
+            }
+            TransactionError::RollbackFailed(id, kind, _) => {
+                write!(
+                    f,
+                    "transaction store failed to roll back transaction {id}: {kind}"
+                )
+            }
+            TransactionError::InvalidTransfer(id) => {
+                write!(
+                    f,
+                    "transfer {id} needs a receiving client other than the sender"
+                )
+            }
+            TransactionError::CrossShardTransfer(id) => write!(
+                f,
+                "transfer {id} is between clients handled by different threads"
+            ),
+            TransactionError::ExcessPrecision(id, amount) => {
+                write!(
+                    f,
+                    "amount {amount} of transaction {id} has too many decimal places"
+                )
+            }
+            TransactionError::MissingAmount(id) => write!(f, "transaction {id} needs an amount"),
+            TransactionError::UnexpectedAmount(id) => {
+                write!(f, "transaction {id} can't have an amount")
+            }
+            TransactionError::ExcessDisputeAmount(id, amount) => write!(
+                f,
+                "amount {amount} exceeds the undisputed part of transaction {id}"
+            ),
+            TransactionError::OutOfOrder(id, timestamp) => write!(
+                f,
+                "transaction {id} at {timestamp} predates the latest transaction of its client"
+            ),
+            TransactionError::OrphanExpired(id) => {
+                write!(f, "transaction {id} didn't arrive in time")
+            }
+            TransactionError::InvalidTransition(id, state, operation) => write!(
+                f,
+                "transaction {id} in state {state:?} doesn't allow operation {operation:?}"
+            ),
+            TransactionError::DisputeLimitReached(id) => {
+                write!(f, "transaction {id} can't be disputed anymore")
+            }
+            TransactionError::NotDisputable(id) => {
+                write!(
+                    f,
+                    "the dispute policy doesn't allow disputing transaction {id}"
+                )
+            }
+            TransactionError::UnlockNotAllowed(client) => {
+                write!(
+                    f,
+                    "unlock records can't reopen the account of client {client}"
+                )
+            }
+            TransactionError::FinalResolve(id) => {
+                write!(f, "transaction {id} was resolved for good")
+            }
+        }
+    }
+}
+
+impl error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TransactionError::AccountError(_, err) => Some(err),
+            TransactionError::RollbackFailed(_, _, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Operation {
     Deposit,
     Withdrawal,
     Dispute,
     Chargeback,
     Resolve,
+    Unlock,
+    Transfer,
+    /// The merchant contests a chargeback, which is reversed.
+    Representment,
+    /// Second-cycle dispute of a represented transaction, to be settled by arbitration with a
+    /// resolve or a chargeback.
+    PreArbitration,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Default)]
+impl Operation {
+    /// Whether the operation moves the funds given by its amount. Other operations refer to an
+    /// earlier transaction, or to the account itself, and have no amount of their own.
+    pub fn has_amount(&self) -> bool {
+        matches!(
+            self,
+            Operation::Deposit | Operation::Withdrawal | Operation::Transfer
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default, serde::Serialize, serde::Deserialize)]
 pub enum TransactionState {
     #[default]
     Ok,
     Disputed,
+    /// The dispute was resolved. The transaction can be disputed again, unless the dispute
+    /// policy makes resolves final.
+    Resolved,
     Chargedback,
+    /// The chargeback was reversed by a representment.
+    Represented,
+    /// Disputed again after a representment, awaiting arbitration.
+    PreArbitration,
+    /// Arbitration ruled for the merchant: the transaction stands for good.
+    Upheld,
+    /// Arbitration ruled for the client: the transaction is reversed for good.
+    Reversed,
+}
+
+impl TransactionState {
+    /// Whether no dispute of the transaction is in progress or can still be contested, so that
+    /// it can be evicted from the dispute window.
+    pub fn is_settled(self) -> bool {
+        matches!(
+            self,
+            TransactionState::Ok
+                | TransactionState::Resolved
+                | TransactionState::Upheld
+                | TransactionState::Reversed
+        )
+    }
+
+    /// State of a transaction once `operation` is applied to it, if the dispute lifecycle
+    /// allows it.
+    ///
+    /// A transaction can be disputed, possibly in several parts, and the dispute resolved or
+    /// charged back. A chargeback can be reversed by a representment, after which a
+    /// pre-arbitration dispute holds the funds again until arbitration settles it for good,
+    /// with a resolve or a chargeback.
+    pub fn transition(self, operation: Operation) -> Option<TransactionState> {
+        use TransactionState::*;
+        match (self, operation) {
+            (Ok | Disputed | Resolved, Operation::Dispute) => Some(Disputed),
+            (Disputed, Operation::Resolve) => Some(Resolved),
+            (Disputed, Operation::Chargeback) => Some(Chargedback),
+            (Chargedback, Operation::Representment) => Some(Represented),
+            (Represented, Operation::PreArbitration) => Some(PreArbitration),
+            (PreArbitration, Operation::Resolve) => Some(Upheld),
+            (PreArbitration, Operation::Chargeback) => Some(Reversed),
+            _ => None,
+        }
+    }
 }
 
+/// Rules deciding which transactions can be disputed.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DisputePolicy {
+    /// Allow withdrawals to be disputed in addition to deposits. A disputed withdrawal is held as
+    /// a provisional credit on the account until it is resolved or charged back.
+    pub withdrawals: bool,
+    /// Maximum number of disputes per transaction, partial ones included.
+    pub max_disputes: Option<u32>,
+    /// Make resolves final: a resolved transaction can't be disputed again.
+    pub final_resolves: bool,
+}
+
+impl DisputePolicy {
+    pub fn allows(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Deposit => true,
+            Operation::Withdrawal => self.withdrawals,
+            Operation::Transfer => true,
+            _ => false,
+        }
+    }
+
+    /// Checks that `transaction`, disputed `disputes` times so far, can be disputed once more.
+    pub fn check_dispute(
+        &self,
+        transaction_id: TransactionId,
+        transaction: &Transaction,
+        disputes: u32,
+    ) -> TransactionResult {
+        if self.final_resolves && transaction.state == TransactionState::Resolved {
+            return Err(TransactionError::FinalResolve(transaction_id));
+        }
+        match self.max_disputes {
+            Some(max) if disputes >= max => {
+                Err(TransactionError::DisputeLimitReached(transaction_id))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How long deposits and withdrawals stay available for disputes.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum DisputeWindow {
+    /// Every transaction can be disputed, however old.
+    #[default]
+    Unbounded,
+    /// Only the last `n` deposits and withdrawals can be disputed. Older ones are evicted from
+    /// the ledger, except those under dispute, which are kept until they are settled.
+    Transactions(usize),
+}
+
+/// How amounts with more decimal places than a [`Precision`] allows are rounded.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum Rounding {
+    /// To the nearest value, ties to the even one (banker's rounding).
+    HalfEven,
+    /// To the nearest value, ties away from zero.
+    HalfUp,
+    /// Towards zero, truncating the extra decimal places.
+    Down,
+    /// Away from zero.
+    Up,
+}
+
+impl From<Rounding> for RoundingStrategy {
+    fn from(value: Rounding) -> Self {
+        match value {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+        }
+    }
+}
+
+/// Number of decimal places accepted in amounts. Amounts with more are rejected, unless a
+/// rounding mode is given to normalize them.
 #[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Precision {
+    pub scale: u32,
+    pub rounding: Option<Rounding>,
+}
+
+impl Default for Precision {
+    /// Four decimal places, the precision of the output, without rounding.
+    fn default() -> Self {
+        Self {
+            scale: 4,
+            rounding: None,
+        }
+    }
+}
+
+impl Precision {
+    /// Checks that `amount` fits this precision, or rounds it to fit if a rounding mode is set.
+    /// Trailing zeros don't count as decimal places.
+    pub fn normalize(
+        &self,
+        transaction_id: TransactionId,
+        amount: Number,
+    ) -> Result<Number, TransactionError> {
+        if amount.normalize().scale() <= self.scale {
+            return Ok(amount);
+        }
+        match self.rounding {
+            Some(rounding) => Ok(amount.round_dp_with_strategy(self.scale, rounding.into())),
+            None => Err(TransactionError::ExcessPrecision(transaction_id, amount)),
+        }
+    }
+}
+
+/// Operations allowed on locked (frozen) accounts. Withdrawals and outgoing transfers are never
+/// allowed on a locked account, regardless of the policy.
+///
+/// By default, new deposits and disputes are blocked too, while disputes that are already open
+/// can still be settled, representments and pre-arbitrations included.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LockPolicy {
+    pub deposits: bool,
+    pub disputes: bool,
+    pub resolves: bool,
+    pub chargebacks: bool,
+    pub representments: bool,
+    pub pre_arbitrations: bool,
+    /// Accept unlock records, which reopen locked accounts. Off by default, so that the
+    /// transaction feed can't undo a chargeback; [`crate::ledger::Ledger::unlock_account`] is
+    /// always available to administrators.
+    pub unlocks: bool,
+}
+
+impl Default for LockPolicy {
+    fn default() -> Self {
+        Self {
+            deposits: false,
+            disputes: false,
+            resolves: true,
+            chargebacks: true,
+            representments: true,
+            pre_arbitrations: true,
+            unlocks: false,
+        }
+    }
+}
+
+impl LockPolicy {
+    /// Still allows deposits and new disputes on locked accounts, which only block withdrawals
+    /// and outgoing transfers.
+    pub fn permissive() -> Self {
+        Self {
+            deposits: true,
+            disputes: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn allows(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Deposit => self.deposits,
+            Operation::Withdrawal | Operation::Transfer => false,
+            Operation::Dispute => self.disputes,
+            Operation::Resolve => self.resolves,
+            Operation::Chargeback => self.chargebacks,
+            Operation::Unlock => true,
+            Operation::Representment => self.representments,
+            Operation::PreArbitration => self.pre_arbitrations,
+        }
+    }
+
+    pub fn check(&self, operation: Operation, account: &mut Account) -> AccountResult {
+        if self.allows(operation) {
+            Ok(())
+        } else {
+            account.check_locked()
+        }
+    }
+}
+
+/// A dispute or resolve of a transaction, identified by the reference of its record, see
+/// [`Transaction::with_reference`].
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DisputeEvent {
+    pub operation: Operation,
+    pub reference: u64,
+}
+
+/// Disputes and resolves a transaction went through.
+///
+/// Only the latest [`DisputeHistory::CAPACITY`] of them are kept, so that histories keep a fixed
+/// size, while `disputes` counts them all. Ledgers keep them apart from their transactions, as
+/// few transactions are ever disputed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DisputeHistory {
+    /// Number of disputes, partial ones included.
+    pub disputes: u32,
+    /// Oldest first.
+    #[serde(default)]
+    events: [Option<DisputeEvent>; DisputeHistory::CAPACITY],
+}
+
+impl DisputeHistory {
+    pub const CAPACITY: usize = 8;
+
+    /// Latest disputes and resolves, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = DisputeEvent> + '_ {
+        self.events.iter().flatten().copied()
+    }
+    pub fn last_dispute(&self) -> Option<u64> {
+        self.last(Operation::Dispute)
+    }
+    pub fn last_resolve(&self) -> Option<u64> {
+        self.last(Operation::Resolve)
+    }
+    fn last(&self, operation: Operation) -> Option<u64> {
+        self.events()
+            .filter(|event| event.operation == operation)
+            .last()
+            .map(|event| event.reference)
+    }
+    /// Appends an event, dropping the oldest one if the history is full.
+    pub(crate) fn push(&mut self, operation: Operation, reference: u64) {
+        if operation == Operation::Dispute {
+            self.disputes += 1;
+        }
+        let event = Some(DisputeEvent {
+            operation,
+            reference,
+        });
+        match self.events.iter().position(Option::is_none) {
+            Some(free) => self.events[free] = event,
+            None => {
+                self.events.rotate_left(1);
+                self.events[Self::CAPACITY - 1] = event;
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     client_id: ClientId,
     amount: Number,
     state: TransactionState,
     operation: Operation,
+    #[serde(default)]
+    currency: Currency,
+    /// Client receiving the funds of a transfer.
+    #[serde(default)]
+    counterparty: Option<ClientId>,
+    /// Part of the amount under dispute, or charged back.
+    #[serde(default)]
+    disputed: Number,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+    /// Stable identifier of the record this transaction comes from, such as its input line.
+    #[serde(default)]
+    reference: Option<u64>,
 }
 
 impl Transaction {
@@ -49,8 +552,44 @@ impl Transaction {
             client_id,
             operation,
             state: TransactionState::default(),
+            currency: Currency::default(),
+            counterparty: None,
+            disputed: Number::ZERO,
+            timestamp: None,
+            reference: None,
         }
     }
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+    pub fn with_counterparty(mut self, counterparty: ClientId) -> Self {
+        self.counterparty = Some(counterparty);
+        self
+    }
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+    /// Identifies the record of this transaction in the dispute history of the transaction it
+    /// disputes or resolves. Records without a reference are identified by the sequence number
+    /// of the ledger applying them, which is only stable if the ledger isn't split.
+    pub fn with_reference(mut self, reference: u64) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+    pub(crate) fn with_amount(mut self, amount: Number) -> Self {
+        self.amount = amount;
+        self
+    }
+    pub(crate) fn with_state(mut self, state: TransactionState) -> Self {
+        self.state = state;
+        self
+    }
+    pub(crate) fn with_disputed(mut self, disputed: Number) -> Self {
+        self.disputed = disputed;
+        self
+    }
     pub fn operation(&self) -> Operation {
         self.operation
     }
@@ -63,48 +602,145 @@ impl Transaction {
     pub fn state(&self) -> TransactionState {
         self.state
     }
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+    pub fn counterparty(&self) -> Option<ClientId> {
+        self.counterparty
+    }
+    pub fn disputed(&self) -> Number {
+        self.disputed
+    }
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+    pub fn reference(&self) -> Option<u64> {
+        self.reference
+    }
+    /// Client whose account holds the funds of this transaction, and therefore the funds held
+    /// while it is disputed: the receiver for transfers, the client itself otherwise.
+    pub fn holder(&self) -> ClientId {
+        match (self.operation, self.counterparty) {
+            (Operation::Transfer, Some(counterparty)) => counterparty,
+            _ => self.client_id,
+        }
+    }
+
+    /// Amount a dispute asking for `requested` puts on hold: the part of the transaction not
+    /// disputed yet if `requested` is zero, `requested` itself if that part covers it.
+    pub fn dispute_amount(
+        &self,
+        transaction_id: TransactionId,
+        requested: Number,
+    ) -> Result<Number, TransactionError> {
+        let undisputed = self.amount - self.disputed;
+        if undisputed.is_zero() {
+            return Err(TransactionError::AlreadyDisputed(transaction_id));
+        }
+        if requested.is_zero() {
+            Ok(undisputed)
+        } else if requested <= undisputed {
+            Ok(requested)
+        } else {
+            Err(TransactionError::ExcessDisputeAmount(
+                transaction_id,
+                requested,
+            ))
+        }
+    }
 
-    pub fn dispute(&mut self, account: &mut Account) -> TransactionResult {
-        account
-            .dispute(self.amount)
-            .map_err(|err| TransactionError::AccountError(self.client_id(), err))?;
-        self.state = TransactionState::Disputed;
+    /// Holds `amount` of this transaction, on top of any part already disputed.
+    pub fn dispute(&mut self, account: &mut Account, amount: Number) -> TransactionResult {
+        match self.operation {
+            Operation::Withdrawal => account.dispute_withdrawal(self.currency, amount),
+            _ => account.dispute(self.currency, amount),
+        }
+        .map_err(|err| TransactionError::AccountError(self.holder(), err))?;
+        self.disputed += amount;
+        self.advance(Operation::Dispute);
         Ok(())
     }
 
+    /// Releases the disputed part of this transaction.
     pub fn resolve(&mut self, account: &mut Account) -> TransactionResult {
-        account
-            .resolve(self.amount)
-            .map_err(|err| TransactionError::AccountError(self.client_id(), err))?;
-        self.state = TransactionState::Ok;
+        match self.operation {
+            Operation::Withdrawal => account.resolve_withdrawal(self.currency, self.disputed),
+            _ => account.resolve(self.currency, self.disputed),
+        }
+        .map_err(|err| TransactionError::AccountError(self.holder(), err))?;
+        self.disputed = Number::ZERO;
+        self.advance(Operation::Resolve);
         Ok(())
     }
 
+    /// Reverses the disputed part of this transaction. The rest of it stands and can't be
+    /// disputed anymore.
     pub fn chargeback(&mut self, account: &mut Account) -> TransactionResult {
-        account.chargeback(self.amount);
-        self.state = TransactionState::Chargedback;
+        match self.operation {
+            Operation::Withdrawal => account
+                .chargeback_withdrawal(self.currency, self.disputed)
+                .map_err(|err| TransactionError::AccountError(self.holder(), err))?,
+            _ => account.chargeback(self.currency, self.disputed),
+        }
+        self.advance(Operation::Chargeback);
+        Ok(())
+    }
+
+    /// Restores the charged back part of this transaction to the account holding it, and lifts
+    /// the lock of the chargeback.
+    pub fn represent(&mut self, account: &mut Account) -> TransactionResult {
+        match self.operation {
+            Operation::Withdrawal => account.represent_withdrawal(self.currency, self.disputed),
+            _ => account.represent(self.currency, self.disputed),
+        }
+        .map_err(|err| TransactionError::AccountError(self.holder(), err))?;
+        self.advance(Operation::Representment);
+        Ok(())
+    }
+
+    /// Holds the represented part of this transaction again, until arbitration.
+    pub fn pre_arbitrate(&mut self, account: &mut Account) -> TransactionResult {
+        match self.operation {
+            Operation::Withdrawal => account.dispute_withdrawal(self.currency, self.disputed),
+            _ => account.dispute(self.currency, self.disputed),
+        }
+        .map_err(|err| TransactionError::AccountError(self.holder(), err))?;
+        self.advance(Operation::PreArbitration);
         Ok(())
     }
 
-    pub fn state_matches_or(
+    /// Moves this transaction to its state after `operation`, which callers must have checked
+    /// with [`Transaction::check_transition`].
+    fn advance(&mut self, operation: Operation) {
+        self.state = self.state.transition(operation).unwrap_or(self.state);
+    }
+
+    /// Checks that the dispute lifecycle allows `operation` on this transaction.
+    pub fn check_transition(
         &self,
-        state: TransactionState,
-        err: TransactionError,
+        transaction_id: TransactionId,
+        operation: Operation,
     ) -> TransactionResult {
-        if self.state == state {
-            Ok(())
-        } else {
-            Err(err)
+        if self.state.transition(operation).is_some() {
+            return Ok(());
         }
+        Err(match operation {
+            Operation::Dispute => TransactionError::AlreadyDisputed(transaction_id),
+            Operation::Resolve | Operation::Chargeback => {
+                TransactionError::UndisputedTransaction(transaction_id)
+            }
+            _ => TransactionError::InvalidTransition(transaction_id, self.state, operation),
+        })
     }
 
     pub fn check_valid_dispute(
         &self,
         transaction_id: TransactionId,
         transaction: &Transaction,
+        policy: &DisputePolicy,
     ) -> TransactionResult {
-        if transaction.operation != Operation::Deposit {
-            return Err(TransactionError::AlreadyDisputed(transaction_id));
+        if !policy.allows(transaction.operation) {
+            return Err(TransactionError::NotDisputable(transaction_id));
         }
         if self.client_id != transaction.client_id {
             return Err(TransactionError::MismatchedClientId(
@@ -112,6 +748,15 @@ impl Transaction {
                 transaction.client_id,
             ));
         }
+        // Records that don't specify a currency refer to the currency of the original
+        // transaction, whatever it is.
+        if self.currency != Currency::NONE && self.currency != transaction.currency {
+            return Err(TransactionError::MismatchedCurrency(
+                transaction_id,
+                self.currency,
+                transaction.currency,
+            ));
+        }
         Ok(())
     }
 }
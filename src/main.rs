@@ -1,15 +1,107 @@
 use clap::Parser;
 use crab::app;
+use crab::transactions::{DisputePolicy, DisputeWindow, LockPolicy, Precision, Rounding};
+use std::{path::PathBuf, process};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Arguments {
+    /// Input CSV file, or `-` to read from stdin
     filename: String,
     #[arg(short, long, default_value_t = false)]
     debug: bool,
+    /// Write a CSV report of rejected transactions to this file
+    #[arg(long)]
+    rejects: Option<PathBuf>,
+    /// Abort on the first record that can't be parsed
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+    /// Order of the accounts in the output
+    #[arg(long, value_enum, default_value_t = app::SortOrder::Client)]
+    sort: app::SortOrder,
+    /// Allow withdrawals to be disputed, not only deposits
+    #[arg(long, default_value_t = false)]
+    dispute_withdrawals: bool,
+    /// Maximum number of disputes of a transaction
+    #[arg(long)]
+    max_disputes: Option<u32>,
+    /// Don't allow resolved transactions to be disputed again
+    #[arg(long, default_value_t = false)]
+    final_resolves: bool,
+    /// Only keep this many deposits and withdrawals available for disputes
+    #[arg(long)]
+    dispute_window: Option<usize>,
+    /// Allow deposits and new disputes on locked accounts
+    #[arg(long, default_value_t = false)]
+    permissive_locks: bool,
+    /// Accept `unlock` records, which reopen locked accounts
+    #[arg(long, default_value_t = false)]
+    allow_unlocks: bool,
+    /// Maximum number of decimal places in amounts
+    #[arg(long, default_value_t = 4)]
+    scale: u32,
+    /// Round amounts with too many decimal places instead of rejecting them
+    #[arg(long, value_enum)]
+    rounding: Option<Rounding>,
+    /// Accept and reorder timestamped records up to this much older than the latest record of
+    /// their client
+    #[arg(long, default_value_t = 0, conflicts_with = "journal")]
+    timestamp_tolerance: u64,
+    /// Keep disputes, resolves and chargebacks of unknown transactions while this many
+    /// transactions are accepted, in case their transaction arrives
+    #[arg(long, default_value_t = 0)]
+    orphan_window: u64,
+    /// Resume from the ledger state in this file and update it after processing
+    #[arg(long)]
+    state: Option<PathBuf>,
+    /// Journal accepted transactions to this file, recovering from it after a crash
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// Number of threads processing transactions, spread by client
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// Number of records buffered for each thread while the input is read
+    #[arg(long, default_value_t = app::DEFAULT_CHANNEL_CAPACITY)]
+    channel_capacity: usize,
 }
 
 fn main() {
     let args = Arguments::parse();
-    app::app(&args.filename, args.debug);
+    let config = app::Config {
+        debug: args.debug,
+        rejects: args.rejects,
+        strict: args.strict,
+        sort: args.sort,
+        dispute_policy: DisputePolicy {
+            withdrawals: args.dispute_withdrawals,
+            max_disputes: args.max_disputes,
+            final_resolves: args.final_resolves,
+        },
+        dispute_window: match args.dispute_window {
+            Some(size) => DisputeWindow::Transactions(size),
+            None => DisputeWindow::Unbounded,
+        },
+        lock_policy: LockPolicy {
+            unlocks: args.allow_unlocks,
+            ..if args.permissive_locks {
+                LockPolicy::permissive()
+            } else {
+                LockPolicy::default()
+            }
+        },
+        precision: Precision {
+            scale: args.scale,
+            rounding: args.rounding,
+        },
+        timestamp_tolerance: args.timestamp_tolerance,
+        orphan_window: args.orphan_window,
+        state: args.state,
+        journal: args.journal,
+        threads: args.threads,
+        channel_capacity: Some(args.channel_capacity),
+    };
+    if let Err(err) = app::app(&args.filename, &config) {
+        eprintln!("error: {}", err);
+        process::exit(err.exit_code());
+    }
 }
@@ -0,0 +1,68 @@
+//! Memory regression tests. They live in their own test binary because they measure the peak
+//! memory of the whole process, which other tests running in parallel would skew.
+#![cfg(target_os = "linux")]
+
+use crab::app::{process_reader, Config};
+use std::io::{self, Read};
+
+/// Peak resident set size of this process, in kilobytes.
+fn peak_memory_kb() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap()
+}
+
+/// Generates `records` disputes of unknown transactions without holding them in memory. The
+/// ledger rejects all of them, so its own memory use stays flat.
+struct GeneratedInput {
+    records: u32,
+    next: u32,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl GeneratedInput {
+    fn new(records: u32) -> Self {
+        Self {
+            records,
+            next: 0,
+            pending: b"type,client,tx,amount\n".to_vec(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for GeneratedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            if self.next == self.records {
+                return Ok(0);
+            }
+            self.pending = format!("dispute,{},{},\n", self.next % 100, self.next).into_bytes();
+            self.position = 0;
+            self.next += 1;
+        }
+        let length = buf.len().min(self.pending.len() - self.position);
+        buf[..length].copy_from_slice(&self.pending[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+#[test]
+fn memory_stays_flat_on_large_input() {
+    let rejects = std::env::temp_dir().join("crab-memory-stays-flat-on-large-input.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let before = peak_memory_kb();
+    let ledger = process_reader(GeneratedInput::new(2_000_000), &config).unwrap();
+    let growth = peak_memory_kb() - before;
+    assert_eq!(ledger.accounts().count(), 0);
+    assert!(growth < 32 * 1024, "peak memory grew by {growth} kB");
+    let _ = std::fs::remove_file(rejects);
+}
@@ -1,41 +1,639 @@
 use crab::account::Account;
 use crab::account::ClientId;
-use crab::app::process_file;
+use crab::app::{
+    checkpoint, load_state, process_file, process_reader, resume_reader, save_state,
+    write_accounts, AppError, Config, SortOrder,
+};
+use crab::transactions::{DisputeWindow, LockPolicy};
 use std::fs::read_to_string;
 
-// TODO: The serialization to CSV method here is different from the one used in main. These should
-// match to prevent breaking changes in serialization in main from happening silently.
-// TODO: output files need to be sorted and we're not verifying the CSV header for correctness.
 #[test]
 fn check_csv_files() {
+    // Files expected to fail have no output, and the exit code of their error.
     let files = [
-        "00-bad_header",
-        "01-bad_record",
-        "02-sample",
-        "03-10k_records",
+        ("00-bad_header", Some(2)),
+        ("01-bad_record", None),
+        ("02-sample", None),
+        ("03-10k_records", None),
     ];
-    for file in files {
+    for (file, exit_code) in files {
         let input_file = format!("tests/data/{file}-input.csv");
         let output_file = format!("tests/data/{file}-output.csv");
-        let ledger = process_file(&input_file, false);
-        let mut results: Vec<(ClientId, Account)> = ledger.into_iter().collect();
-        let references: Vec<String> = read_to_string(output_file)
-            .unwrap() // panic on possible file-reading errors
-            .lines() // split the string into an iterator of string slices
-            .map(String::from) // make each slice into a string
+        let mut output = Vec::new();
+        match process_file(&input_file, &Config::default()) {
+            Ok(ledger) => write_accounts(&ledger, SortOrder::Client, &mut output).unwrap(),
+            Err(err) => assert_eq!(Some(err.exit_code()), exit_code, "error on file {file}"),
+        }
+        let reference = read_to_string(output_file).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            reference,
+            "mismatched result on file {file}"
+        );
+    }
+}
+
+#[test]
+fn sort_accounts() {
+    let input = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,3.0
+deposit,3,3,2.0
+dispute,3,3,
+deposit,4,4,2.5
+dispute,4,4,
+chargeback,4,4,
+";
+    let ledger = process_reader(input.as_bytes(), &Config::default()).unwrap();
+    let order = |sort| {
+        let mut output = Vec::new();
+        write_accounts(&ledger, sort, &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
             .skip(1)
-            .collect();
-        results.sort_by_key(|(key, _)| *key);
-        for ((key, account), reference) in results.into_iter().zip(references) {
-            let serialized = format!(
-                "{},{:.4},{:.4},{:.4},{}",
-                key.0,
-                account.available(),
-                account.held(),
-                account.total(),
-                account.locked(),
-            );
-            assert_eq!(serialized, reference, "mismatched result on file {file}");
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+    assert_eq!(order(SortOrder::Client), "1,2,3,4");
+    assert_eq!(order(SortOrder::Total), "2,3,1,4");
+    assert_eq!(order(SortOrder::Available), "2,1,3,4");
+    assert_eq!(order(SortOrder::LockedFirst), "4,1,2,3");
+}
+
+#[test]
+fn process_in_memory_reader() {
+    let input = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1.0\n";
+    let ledger = process_reader(input.as_bytes(), &Config::default()).unwrap();
+    let results: Vec<(ClientId, Account)> = ledger.into_iter().collect();
+    assert_eq!(results.len(), 1);
+    let (key, account) = &results[0];
+    assert_eq!(*key, ClientId(1));
+    assert_eq!(format!("{:.4}", account.available()), "1.5000");
+    assert_eq!(format!("{:.4}", account.held()), "0.0000");
+}
+
+#[test]
+fn write_rejects_report() {
+    let rejects = std::env::temp_dir().join("crab-write-rejects-report.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,1,1.0\nwithdrawal,1,2,3.0\n";
+    process_reader(input.as_bytes(), &config).unwrap();
+    let report: Vec<String> = read_to_string(&rejects)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(report[0], "line,record,code,message");
+    assert!(report[1].starts_with("3,\"deposit,1,1,1.0\",repeated_transaction_id,"));
+    assert!(report[2].starts_with("4,\"withdrawal,1,2,3.0\",account_underflow,"));
+    assert_eq!(report.len(), 3);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn rejects_write_failure_is_an_error() {
+    let config = Config {
+        rejects: Some("/dev/full".into()),
+        ..Config::default()
+    };
+    let input = "type,client,tx,amount\nwithdrawal,1,1,3.0\n";
+    let err = process_reader(input.as_bytes(), &config).err().unwrap();
+    assert!(matches!(err, AppError::Io(_)));
+    assert_eq!(err.exit_code(), 1);
+}
+
+#[test]
+fn bad_header_is_an_error() {
+    let res = process_file(
+        &String::from("tests/data/00-bad_header-input.csv"),
+        &Config::default(),
+    );
+    let err = res.err().unwrap();
+    assert!(matches!(err, AppError::BadHeader(_)));
+    assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn malformed_records_are_reported() {
+    let rejects = std::env::temp_dir().join("crab-malformed-records-are-reported.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let ledger =
+        process_file(&String::from("tests/data/01-bad_record-input.csv"), &config).unwrap();
+    assert_eq!(ledger.into_iter().count(), 1);
+    let report: Vec<String> = read_to_string(&rejects)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    assert!(report[1].starts_with("3,\"deposits,1,2,2.0\",malformed_record,"));
+    assert!(report[2].starts_with("4,\"deposit,1,2,2.0,asdf\",malformed_record,"));
+    assert!(report[3].starts_with("5,\"withdrawal,1,3,3.0\",account_underflow,"));
+    assert_eq!(report.len(), 4);
+}
+
+#[test]
+fn strict_mode_stops_on_malformed_record() {
+    let config = Config {
+        strict: true,
+        ..Config::default()
+    };
+    let res = process_file(&String::from("tests/data/01-bad_record-input.csv"), &config);
+    match res.err().unwrap() {
+        AppError::MalformedRecord { line, byte, .. } => {
+            assert_eq!(line, 3);
+            assert_eq!(byte, 38);
         }
+        err => panic!("unexpected error {:?}", err),
     }
 }
+
+#[test]
+fn resume_from_saved_state() {
+    let state = std::env::temp_dir().join("crab-resume-from-saved-state.json");
+    let _ = std::fs::remove_file(&state);
+    let config = Config {
+        state: Some(state.clone()),
+        ..Config::default()
+    };
+    let ledger = load_state(&state, &config).unwrap();
+    let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,3.0\n";
+    let ledger = resume_reader(ledger, input.as_bytes(), &config).unwrap();
+    save_state(&ledger, &state).unwrap();
+
+    let ledger = load_state(&state, &config).unwrap();
+    let input = "type,client,tx,amount\ndispute,1,2,\n";
+    let ledger = resume_reader(ledger, input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,2.0000,3.0000,5.0000,false\n"
+    );
+}
+
+#[test]
+fn recover_from_journal_after_crash() {
+    let journal = std::env::temp_dir().join("crab-recover-from-journal-after-crash.log");
+    let _ = std::fs::remove_file(&journal);
+    let config = Config {
+        journal: Some(journal.clone()),
+        ..Config::default()
+    };
+    let input = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,3,50.0
+dispute,1,1,
+resolve,1,1,
+withdrawal,1,2,3.0
+dispute,1,1,
+";
+    // A run that dies after processing the first lines leaves them in the journal only, the
+    // rejected withdrawal as an aborted entry.
+    let partial: String = input
+        .lines()
+        .take(4)
+        .map(|line| format!("{line}\n"))
+        .collect();
+    resume_reader(config.ledger(), partial.as_bytes(), &config).unwrap();
+
+    let ledger = resume_reader(config.ledger(), input.as_bytes(), &config).unwrap();
+    checkpoint(&ledger, &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,-3.0000,10.0000,7.0000,false\n"
+    );
+    assert_eq!(std::fs::metadata(&journal).unwrap().len(), 0);
+}
+
+#[test]
+fn write_balances_per_currency() {
+    let input = "type,client,tx,amount,currency
+deposit,1,1,1.0,EUR
+deposit,1,2,2.0,usd
+deposit,2,3,3.0,
+withdrawal,1,4,0.5,EUR
+dispute,1,2,,EUR
+deposit,1,5,1.0,EURO
+";
+    let ledger = process_reader(input.as_bytes(), &Config::default()).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,currency,available,held,total,locked
+1,EUR,0.5000,0.0000,0.5000,false
+1,USD,2.0000,0.0000,2.0000,false
+2,XXX,3.0000,0.0000,3.0000,false
+"
+    );
+}
+
+#[test]
+fn locked_account_rejects_deposits_by_default() {
+    let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,2.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,3,5.0
+";
+    let balance = |config: &Config| {
+        let ledger = process_reader(input.as_bytes(), config).unwrap();
+        let (_, account) = ledger.into_iter().next().unwrap();
+        assert!(account.locked());
+        format!("{:.4}", account.available())
+    };
+    assert_eq!(balance(&Config::default()), "2.0000");
+    let config = Config {
+        lock_policy: LockPolicy::permissive(),
+        ..Config::default()
+    };
+    assert_eq!(balance(&config), "7.0000");
+}
+
+#[test]
+fn sharded_processing_matches_sequential() {
+    for file in ["01-bad_record", "02-sample", "03-10k_records"] {
+        let input_file = format!("tests/data/{file}-input.csv");
+        let output_file = format!("tests/data/{file}-output.csv");
+        let config = Config {
+            threads: 4,
+            ..Config::default()
+        };
+        let ledger = process_file(&input_file, &config).unwrap();
+        let mut output = Vec::new();
+        write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+        let reference = read_to_string(output_file).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            reference,
+            "mismatched result on file {file}"
+        );
+    }
+}
+
+#[test]
+fn sharded_rejects_match_sequential() {
+    let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,2,1,
+dispute,3,1,
+deposit,3,1,1.0
+dispute,4,5,
+deposit,2,5,1.0
+withdrawal,1,3,2.0
+dispute,2,3,
+dispute,1,1,
+resolve,2,1,
+deposit,4,6,3.0
+deposit,1,6,3.0
+";
+    let rejects_with = |threads: usize| {
+        let rejects = std::env::temp_dir().join(format!("crab-sharded-rejects-{threads}.csv"));
+        let config = Config {
+            rejects: Some(rejects.clone()),
+            threads,
+            ..Config::default()
+        };
+        process_reader(input.as_bytes(), &config).unwrap();
+        let mut report: Vec<String> = read_to_string(&rejects)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        report.sort();
+        report
+    };
+    let sequential = rejects_with(1);
+    assert_eq!(sequential.len(), 8);
+    assert!(sequential[1].starts_with("12,\"resolve,2,1,\",mismatched_client_id,"));
+    assert_eq!(rejects_with(4), sequential);
+}
+
+#[test]
+fn orphan_window_with_threads_is_a_config_error() {
+    let config = Config {
+        orphan_window: 2,
+        threads: 2,
+        ..Config::default()
+    };
+    let input = "type,client,tx,amount\n";
+    let err = process_reader(input.as_bytes(), &config).err().unwrap();
+    assert!(matches!(err, AppError::Config(_)));
+    assert_eq!(err.exit_code(), 6);
+}
+
+#[test]
+fn dispute_window_cant_be_split_across_threads() {
+    let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,10.0
+deposit,2,3,10.0
+dispute,1,1,
+";
+    let config = Config {
+        dispute_window: DisputeWindow::Transactions(1),
+        ..Config::default()
+    };
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,20.0000,0.0000,20.0000,false
+"
+    );
+
+    let config = Config {
+        threads: 2,
+        ..config
+    };
+    let err = process_reader(input.as_bytes(), &config).err().unwrap();
+    assert!(matches!(err, AppError::Config(_)));
+}
+
+#[test]
+fn recover_sharded_run_from_journal() {
+    let journal = std::env::temp_dir().join("crab-recover-sharded-run-from-journal.log");
+    let _ = std::fs::remove_file(&journal);
+    let config = Config {
+        journal: Some(journal.clone()),
+        threads: 2,
+        ..Config::default()
+    };
+    let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,1,1,
+withdrawal,2,3,1.0
+resolve,1,1,
+deposit,2,4,2.0
+";
+    // The run died after client 1 processed its first two lines and client 2 its first one.
+    let partial = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,1,1,
+";
+    resume_reader(config.ledger(), partial.as_bytes(), &config).unwrap();
+
+    let ledger = resume_reader(config.ledger(), input.as_bytes(), &config).unwrap();
+    assert_eq!(ledger.sequence(), 6);
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,6.0000,0.0000,6.0000,false
+"
+    );
+}
+
+#[test]
+fn transfer_between_clients() {
+    let input = "type,client,tx,amount,to
+deposit,1,1,10.0,
+transfer,1,2,4.0,2
+transfer,2,3,5.0,1
+transfer,1,4,1.0,
+dispute,1,2,,
+";
+    let ledger = process_reader(input.as_bytes(), &Config::default()).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked
+1,6.0000,0.0000,6.0000,false
+2,0.0000,4.0000,4.0000,false
+"
+    );
+}
+
+#[test]
+fn transfers_cant_be_split_across_threads() {
+    let input = "type,client,tx,amount,to
+deposit,1,1,10.0,
+transfer,1,2,4.0,2
+";
+    let config = Config {
+        threads: 4,
+        ..Config::default()
+    };
+    let err = process_reader(input.as_bytes(), &config).err().unwrap();
+    assert!(matches!(err, AppError::Config(_)));
+    assert_eq!(err.exit_code(), 6);
+}
+
+#[test]
+fn reject_amounts_with_excess_precision() {
+    let rejects = std::env::temp_dir().join("crab-reject-amounts-with-excess-precision.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let input = "type,client,tx,amount\ndeposit,1,1,1.00005\ndeposit,1,2,1.5000\n";
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+    );
+    let report = read_to_string(&rejects).unwrap();
+    assert!(report
+        .lines()
+        .nth(1)
+        .unwrap()
+        .starts_with("2,\"deposit,1,1,1.00005\",excess_precision,"));
+}
+
+#[test]
+fn reject_missing_or_unexpected_amounts() {
+    let rejects = std::env::temp_dir().join("crab-reject-missing-or-unexpected-amounts.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let input = "\
+type,client,tx,amount
+deposit,1,1,
+deposit,1,1,2.0
+withdrawal,1,2,
+resolve,1,1,2.0
+deposit,1,3,0
+";
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n"
+    );
+    let report = read_to_string(&rejects).unwrap();
+    let report: Vec<&str> = report.lines().collect();
+    assert_eq!(report.len(), 5);
+    assert!(report[1].starts_with("2,\"deposit,1,1,\",missing_amount,"));
+    assert!(report[2].starts_with("4,\"withdrawal,1,2,\",missing_amount,"));
+    assert!(report[3].starts_with("5,\"resolve,1,1,2.0\",unexpected_amount,"));
+    assert!(report[4].starts_with("6,\"deposit,1,3,0\",invalid_amount,"));
+}
+
+#[test]
+fn reorder_timestamped_records() {
+    let rejects = std::env::temp_dir().join("crab-reorder-timestamped-records.csv");
+    let input = "\
+type,client,tx,amount,timestamp
+withdrawal,1,2,5.0,105
+deposit,1,1,10.0,100
+deposit,1,3,1.0,120
+deposit,1,4,1.0,90
+";
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        timestamp_tolerance: 10,
+        ..Config::default()
+    };
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let account = ledger.accounts().next().unwrap().1;
+    assert_eq!(format!("{:.4}", account.available()), "6.0000");
+    let report = read_to_string(&rejects).unwrap();
+    let report: Vec<&str> = report.lines().collect();
+    assert_eq!(report.len(), 2);
+    assert!(report[1].starts_with("5,\"deposit,1,4,1.0,90\",out_of_order,"));
+
+    // Without tolerance, records are applied in input order. Rejected records don't count as
+    // the latest of their client.
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let account = ledger.accounts().next().unwrap().1;
+    assert_eq!(format!("{:.4}", account.available()), "11.0000");
+    let report = read_to_string(&rejects).unwrap();
+    let report: Vec<&str> = report.lines().collect();
+    assert_eq!(report.len(), 3);
+    assert!(report[1].starts_with("2,\"withdrawal,1,2,5.0,105\",account_underflow,"));
+    assert!(report[2].starts_with("5,\"deposit,1,4,1.0,90\",out_of_order,"));
+}
+
+#[test]
+fn reject_records_older_than_held_back_ones() {
+    let rejects = std::env::temp_dir().join("crab-reject-records-older-than-held-back-ones.csv");
+    let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,100
+deposit,1,2,1.0,85
+deposit,2,3,1.0,85
+";
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        timestamp_tolerance: 10,
+        ..Config::default()
+    };
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,1.0000,0.0000,1.0000,false
+"
+    );
+    let report = read_to_string(&rejects).unwrap();
+    let report: Vec<&str> = report.lines().collect();
+    assert_eq!(report.len(), 2);
+    assert!(report[1].starts_with("3,\"deposit,1,2,1.0,85\",out_of_order,"));
+
+    let journal = std::env::temp_dir().join("crab-reject-records-older-than-held-back-ones.log");
+    let config = Config {
+        journal: Some(journal),
+        ..config
+    };
+    let err = process_reader(input.as_bytes(), &config).err().unwrap();
+    assert!(matches!(err, AppError::Config(_)));
+}
+
+#[test]
+fn apply_orphan_disputes_when_their_deposit_arrives() {
+    let rejects = std::env::temp_dir().join("crab-apply-orphan-disputes.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        orphan_window: 2,
+        ..Config::default()
+    };
+    let input = "\
+type,client,tx,amount
+dispute,1,1,
+deposit,1,1,10.0
+dispute,2,5,
+deposit,2,2,5.0
+deposit,2,3,5.0
+deposit,2,4,5.0
+chargeback,2,6,
+";
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         1,0.0000,10.0000,10.0000,false\n\
+         2,15.0000,0.0000,15.0000,false\n"
+    );
+    let report = read_to_string(&rejects).unwrap();
+    let report: Vec<&str> = report.lines().collect();
+    assert_eq!(report.len(), 3);
+    // Expired once more than two transactions were accepted after it.
+    assert!(report[1].starts_with("4,\"dispute,2,5,\",orphan_expired,"));
+    // Still waiting at the end of the input.
+    assert!(report[2].starts_with("8,\"chargeback,2,6,\",orphan_expired,"));
+}
+
+#[test]
+fn represent_chargeback_and_arbitrate() {
+    let rejects = std::env::temp_dir().join("crab-represent-chargeback-and-arbitrate.csv");
+    let config = Config {
+        rejects: Some(rejects.clone()),
+        ..Config::default()
+    };
+    let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+prearbitration,1,1,
+dispute,1,1,
+chargeback,1,1,
+representment,1,1,
+representment,1,1,
+prearbitration,1,1,
+resolve,1,1,
+";
+    let ledger = process_reader(input.as_bytes(), &config).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&ledger, SortOrder::Client, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    let report = read_to_string(&rejects).unwrap();
+    let report: Vec<&str> = report.lines().collect();
+    assert_eq!(report.len(), 3);
+    assert!(report[1].starts_with("3,\"prearbitration,1,1,\",invalid_transition,"));
+    assert!(report[2].starts_with("7,\"representment,1,1,\",invalid_transition,"));
+}